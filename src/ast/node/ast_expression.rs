@@ -1,9 +1,13 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use crate::runtime::value::Value;
+use parking_lot::RwLock;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::runtime::accessor::{access, Accessor};
+use crate::runtime::frame::Frame;
+use crate::runtime::name::Name;
+use crate::runtime::value::{Value, ValueType};
 
-type Wrap<T> = Rc<RefCell<T>>;
-type WrapExpression = Wrap<ASTExpression>;
+pub type Wrap<T> = Arc<RwLock<T>>;
+pub type WrapExpression = Wrap<ASTExpression>;
 
 #[derive(Debug)]
 pub enum ExpressionType {
@@ -13,6 +17,10 @@ pub enum ExpressionType {
     PrimaryExpression(ASTPrimaryExpression),
     OrExpression(ASTOrExpression),
     AndExpression(ASTAndExpression),
+    NotExpression(ASTNotExpression),
+    ObjectPattern(ASTObjectPattern),
+    OptionalExpression(ASTOptionalExpression),
+    AggregateExpression(ASTAggregateExpression),
 }
 
 #[derive(Debug)]
@@ -31,31 +39,141 @@ impl ASTExpression {
     pub fn as_loose_string(&self) -> Option<String> {
         match &self.ast_type {
             ExpressionType::String(s) => Some(s.value.clone()),
-            ExpressionType::Number(n) => Some(f64::to_string(&n.value)),
+            ExpressionType::Number(n) => n.value.clone().to_value().as_text(),
             _ => None,
         }
     }
 
+    /// Whether this is a plain variable, that a query can bind. A variable with a path (`?x.id`) is not,
+    /// it only reads from a bound variable
     pub fn is_var(&self) -> bool {
+        matches!(&self.ast_type, ExpressionType::Variable(x) if !x.is_path)
+    }
+
+    pub fn get_var_name(&self) -> Option<Name> {
         match &self.ast_type {
-            ExpressionType::Variable(_) => true,
-            _ => false
+            ExpressionType::Variable(x) if !x.is_path => Some(x.name.clone()),
+            _ => None
         }
     }
 
-    pub fn get_var_name(&self) -> Option<String> {
+    pub fn is_path_var(&self) -> bool {
+        matches!(&self.ast_type, ExpressionType::Variable(x) if x.is_path)
+    }
+
+    /// The value of a constant, a bound variable or a path into a bound variable, in `frame`
+    pub fn value_in_frame(&self, frame: &Frame) -> Option<Value> {
         match &self.ast_type {
-            ExpressionType::Variable(x) => Some(x.name.clone()),
-            _ => None
+            ExpressionType::Variable(x) => {
+                let v = frame.get(&x.name)?;
+                if x.is_path {
+                    Some(access(v, &x.path)?.clone())
+                } else {
+                    Some(v.clone())
+                }
+            },
+            _ => self.try_to_value()
         }
     }
 
-    pub fn try_to_value(&self) -> Option<Value> {
+    /// Names of all predicates used in this expression, including the ones in nested expressions
+    pub fn predicates(&self) -> Vec<String> {
+        match &self.ast_type {
+            ExpressionType::PrimaryExpression(p) => vec![p.predicate.clone()],
+            ExpressionType::AndExpression(a) => {
+                let mut result = a.left.read().predicates();
+                result.append(&mut a.right.read().predicates());
+                result
+            },
+            ExpressionType::OrExpression(a) => {
+                let mut result = a.left.read().predicates();
+                result.append(&mut a.right.read().predicates());
+                result
+            },
+            ExpressionType::NotExpression(n) => n.expression.read().predicates(),
+            ExpressionType::OptionalExpression(o) => o.expression.read().predicates(),
+            ExpressionType::AggregateExpression(a) => a.expression.read().predicates(),
+            _ => Vec::new()
+        }
+    }
+
+    /// Names of the predicates used inside a negation, an `optional` or an aggregate in this expression,
+    /// whose result depends on all the answers of the predicate being known
+    pub fn negated_predicates(&self) -> Vec<String> {
         match &self.ast_type {
-            ExpressionType::Number(n) => {
-                let number = n.value;
-                Some(Value::from_number(number))
+            ExpressionType::AndExpression(a) => {
+                let mut result = a.left.read().negated_predicates();
+                result.append(&mut a.right.read().negated_predicates());
+                result
+            },
+            ExpressionType::OrExpression(a) => {
+                let mut result = a.left.read().negated_predicates();
+                result.append(&mut a.right.read().negated_predicates());
+                result
+            },
+            ExpressionType::NotExpression(n) => n.expression.read().predicates(),
+            ExpressionType::OptionalExpression(o) => o.expression.read().predicates(),
+            ExpressionType::AggregateExpression(a) => a.expression.read().predicates(),
+            _ => Vec::new()
+        }
+    }
+
+    /// Names of all variables used in this expression, without duplicates
+    pub fn variables(&self) -> Vec<String> {
+        self.variables_except(None)
+    }
+
+    /// Names of the variables used in this expression, ignoring the ones only used in the sub expression `except`
+    pub fn variables_except(&self, except: Option<&ASTExpression>) -> Vec<String> {
+        if except.is_some_and(|x| std::ptr::eq(x, self)) {
+            return Vec::new();
+        }
+
+        let mut result: Vec<String> = Vec::new();
+        let mut push_all = |names: Vec<String>| {
+            for name in names {
+                if !result.contains(&name) {
+                    result.push(name);
+                }
+            }
+        };
+
+        match &self.ast_type {
+            ExpressionType::Variable(x) => push_all(vec![x.name.to_string()]),
+            ExpressionType::PrimaryExpression(p) => {
+                for arg in p.args.iter() {
+                    push_all(arg.read().variables_except(except));
+                }
+            },
+            ExpressionType::AndExpression(a) => {
+                push_all(a.left.read().variables_except(except));
+                push_all(a.right.read().variables_except(except));
             },
+            ExpressionType::OrExpression(a) => {
+                push_all(a.left.read().variables_except(except));
+                push_all(a.right.read().variables_except(except));
+            },
+            ExpressionType::NotExpression(n) => push_all(n.expression.read().variables_except(except)),
+            ExpressionType::OptionalExpression(o) => push_all(o.expression.read().variables_except(except)),
+            // the other variables of an aggregate are local to it
+            ExpressionType::AggregateExpression(a) => {
+                push_all(vec![a.result.to_string()]);
+                push_all(a.group_by.iter().map(|x| x.to_string()).collect());
+            },
+            ExpressionType::ObjectPattern(o) => {
+                for (_, field) in o.fields.iter() {
+                    push_all(field.read().variables_except(except));
+                }
+            },
+            _ => {}
+        }
+
+        result
+    }
+
+    pub fn try_to_value(&self) -> Option<Value> {
+        match &self.ast_type {
+            ExpressionType::Number(n) => Some(n.value.clone().to_value()),
             ExpressionType::String(s) => {
                 Some(Value::from_string(s.value.as_str()))
             }
@@ -75,13 +193,37 @@ impl ASTExpression {
         }
     }
 
+    pub fn from_not_expression(expr: ASTNotExpression) -> Self {
+        ASTExpression {
+            ast_type: ExpressionType::NotExpression(expr)
+        }
+    }
+
+    pub fn from_optional_expression(expr: ASTOptionalExpression) -> Self {
+        ASTExpression {
+            ast_type: ExpressionType::OptionalExpression(expr)
+        }
+    }
+
+    pub fn from_aggregate_expression(expr: ASTAggregateExpression) -> Self {
+        ASTExpression {
+            ast_type: ExpressionType::AggregateExpression(expr)
+        }
+    }
+
+    pub fn from_object_pattern(pattern: ASTObjectPattern) -> Self {
+        ASTExpression {
+            ast_type: ExpressionType::ObjectPattern(pattern)
+        }
+    }
+
     pub fn from_primary_expression(expr: ASTPrimaryExpression) -> Self {
         ASTExpression {
             ast_type: ExpressionType::PrimaryExpression(expr)
         }
     }
 
-    pub fn from_number(number: f64) -> Self {
+    pub fn from_number(number: ValueType) -> Self {
         ASTExpression {
             ast_type: ExpressionType::Number(ASTNumber {
                 value: number
@@ -89,7 +231,7 @@ impl ASTExpression {
         }
     }
 
-    pub fn from_str(s: &str) -> Self {
+    pub fn from_string(s: &str) -> Self {
         ASTExpression {
             ast_type: ExpressionType::String(ASTString {
                 value: String::from(s)
@@ -100,22 +242,107 @@ impl ASTExpression {
     pub fn from_variable(name: &str, is_path: bool) -> Self {
         ASTExpression {
             ast_type: ExpressionType::Variable(ASTVariable {
-                name: String::from(name),
-                is_path
+                name: Name::new(name),
+                is_path,
+                path: Vec::new(),
+            })
+        }
+    }
+
+    pub fn from_path_variable(name: &str, path: Vec<Accessor>) -> Self {
+        ASTExpression {
+            ast_type: ExpressionType::Variable(ASTVariable {
+                name: Name::new(name),
+                is_path: true,
+                path,
             })
         }
     }
 }
 
+/// Writes the expression back in query syntax
+impl Display for ASTExpression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // operands of `&&` and `!` that bind looser than them are parenthesized
+        fn operand(f: &mut Formatter<'_>, ast: &WrapExpression, allow_and: bool) -> std::fmt::Result {
+            let ast = ast.read();
+            match &ast.ast_type {
+                ExpressionType::OrExpression(_) => write!(f, "({})", ast),
+                ExpressionType::AndExpression(_) if !allow_and => write!(f, "({})", ast),
+                _ => write!(f, "{}", ast),
+            }
+        }
+
+        match &self.ast_type {
+            ExpressionType::Number(n) => write!(f, "{}", n.value),
+            ExpressionType::String(s) => write!(f, "{}", serde_json::Value::String(s.value.clone())),
+            ExpressionType::Variable(x) => {
+                write!(f, "{}", x.name)?;
+                x.path.iter().try_for_each(|a| write!(f, "{}", a))
+            },
+            ExpressionType::PrimaryExpression(p) => {
+                write!(f, "{}", p.predicate)?;
+                p.path.iter().try_for_each(|a| write!(f, "{}", a))?;
+                p.args.iter().try_for_each(|a| write!(f, " {}", a.read()))
+            },
+            ExpressionType::AndExpression(a) => {
+                operand(f, &a.left, true)?;
+                write!(f, " && ")?;
+                operand(f, &a.right, false)
+            },
+            ExpressionType::OrExpression(o) => write!(f, "{} || {}", o.left.read(), o.right.read()),
+            ExpressionType::NotExpression(n) => {
+                write!(f, "!")?;
+                operand(f, &n.expression, false)
+            },
+            ExpressionType::OptionalExpression(o) => {
+                write!(f, "optional ")?;
+                operand(f, &o.expression, false)
+            },
+            ExpressionType::AggregateExpression(a) => {
+                write!(f, "{} {}", a.op.name(), a.result)?;
+                if let Some(x) = &a.value {
+                    write!(f, " {}", x)?;
+                }
+                write!(f, " {{ {} }}", a.expression.read())?;
+                if !a.group_by.is_empty() {
+                    write!(f, " by {}", a.group_by.join(", "))?;
+                }
+                Ok(())
+            },
+            ExpressionType::ObjectPattern(o) if o.fields.is_empty() => write!(f, "{{}}"),
+            ExpressionType::ObjectPattern(o) => {
+                write!(f, "{{ ")?;
+                for (index, (key, value)) in o.fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    let plain = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    if plain {
+                        write!(f, "{}: {}", key, value.read())?;
+                    } else {
+                        write!(f, "{}: {}", serde_json::Value::String(key.clone()), value.read())?;
+                    }
+                }
+                write!(f, " }}")
+            },
+        }
+    }
+}
+
+/// A number constant, `Int` or `Float`
 #[derive(Debug)]
 pub struct ASTNumber {
-    pub value: f64,
+    pub value: ValueType,
 }
 
 #[derive(Debug)]
 pub struct ASTVariable {
-    pub name: String,
+    pub name: Name,
     pub is_path: bool,
+    /// the path after the variable name, for example, `nameTextMapHash` in `?x.nameTextMapHash`
+    pub path: Vec<Accessor>,
 }
 
 #[derive(Debug)]
@@ -125,10 +352,20 @@ pub struct ASTString {
 
 #[derive(Debug)]
 pub struct ASTPrimaryExpression {
-    pub predicate: Vec<String>,
+    /// a file name, a locale, a rule or a built-in function
+    pub predicate: String,
+    /// path into each row of a file, for example, `nameTextMapHash` in `WeaponExcelConfigData.nameTextMapHash`
+    pub path: Vec<Accessor>,
     pub args: Vec<WrapExpression>,
 }
 
+/// A pattern like `{ qualityType: "QUALITY_PURPLE", weaponType: ?wt }`, matching any object that has
+/// all the listed keys, with each value matching its sub pattern
+#[derive(Debug)]
+pub struct ASTObjectPattern {
+    pub fields: Vec<(String, WrapExpression)>,
+}
+
 #[derive(Debug)]
 pub struct ASTAndExpression {
     pub left: WrapExpression,
@@ -155,4 +392,77 @@ impl ASTOrExpression {
             left, right
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub struct ASTNotExpression {
+    pub expression: WrapExpression,
+}
+
+impl ASTNotExpression {
+    pub fn new(expression: WrapExpression) -> Self {
+        Self {
+            expression
+        }
+    }
+}
+
+/// `optional` expression, which keeps a frame even when the inner expression has no answer for it,
+/// binding the variables of the inner expression to null instead
+#[derive(Debug)]
+pub struct ASTOptionalExpression {
+    pub expression: WrapExpression,
+}
+
+impl ASTOptionalExpression {
+    pub fn new(expression: WrapExpression) -> Self {
+        Self {
+            expression
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl AggregateOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AggregateOp::Count => "count",
+            AggregateOp::Sum => "sum",
+            AggregateOp::Min => "min",
+            AggregateOp::Max => "max",
+            AggregateOp::Avg => "avg",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "count" => Some(AggregateOp::Count),
+            "sum" => Some(AggregateOp::Sum),
+            "min" => Some(AggregateOp::Min),
+            "max" => Some(AggregateOp::Max),
+            "avg" => Some(AggregateOp::Avg),
+            _ => None
+        }
+    }
+}
+
+/// Aggregate over the answers of an expression, for example, `count ?n { ... } by ?group` or `sum ?total ?x { ... }`
+#[derive(Debug)]
+pub struct ASTAggregateExpression {
+    pub op: AggregateOp,
+    /// the variable bound to the result of the aggregate
+    pub result: Name,
+    /// the aggregated variable, `None` for `count`, which counts the answers
+    pub value: Option<Name>,
+    pub expression: WrapExpression,
+    /// answers are aggregated separately for each combination of values of these variables
+    pub group_by: Vec<Name>,
+}
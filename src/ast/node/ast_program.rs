@@ -0,0 +1,33 @@
+use crate::ast::node::ast_expression::WrapExpression;
+
+#[derive(Debug)]
+pub struct ASTRuleDefinition {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: WrapExpression,
+}
+
+#[derive(Debug)]
+pub struct ASTProgram {
+    pub rules: Vec<ASTRuleDefinition>,
+    pub expression: Option<WrapExpression>,
+    /// variables listed by `select ?a, ?b where ...`, in order
+    pub select: Option<Vec<String>>,
+    pub modifiers: ASTModifiers,
+}
+
+/// `?x desc` in `order by ?x desc`
+#[derive(Debug, Clone)]
+pub struct ASTOrderKey {
+    pub variable: String,
+    pub descending: bool,
+}
+
+/// Modifiers applied to the results of a query, in the order `order by`, `distinct`, `offset`, `limit`
+#[derive(Debug, Clone, Default)]
+pub struct ASTModifiers {
+    pub distinct: bool,
+    pub order_by: Vec<ASTOrderKey>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
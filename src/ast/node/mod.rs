@@ -1 +1,2 @@
 pub mod ast_expression;
+pub mod ast_program;
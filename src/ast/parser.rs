@@ -1,9 +1,12 @@
-use std::cell::RefCell;
-use std::error::Error;
-use std::rc::Rc;
+use parking_lot::RwLock;
+use std::sync::Arc;
 use pest::iterators::Pair;
 use pest::Parser;
-use crate::ast::node::ast_expression::{ASTAndExpression, ASTExpression, ASTOrExpression, ASTPrimaryExpression};
+use crate::ast::node::ast_expression::{AggregateOp, ASTAggregateExpression, ASTAndExpression, ASTExpression, ASTNotExpression, ASTObjectPattern, ASTOptionalExpression, ASTOrExpression, ASTPrimaryExpression};
+use crate::ast::node::ast_program::{ASTModifiers, ASTOrderKey, ASTProgram, ASTRuleDefinition};
+use crate::runtime::accessor::Accessor;
+use crate::runtime::name::Name;
+use crate::runtime::value::ValueType;
 
 #[derive(Parser)]
 #[grammar = "gdp.pest"]
@@ -16,9 +19,37 @@ pub fn parse<'i>(input: &'i str) -> Option<Pair<'i, Rule>> {
     Some(parsed)
 }
 
+/// Variables introduced by the parser start with this prefix, which cannot appear in a query
+pub const HIDDEN_VARIABLE_PREFIX: &str = "?#";
+
+/// Resolve the escape sequences allowed by the `char` rule, for example, `\\w` becomes `\w`
+fn unescape(s: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'b' => result.push('\u{8}'),
+            'f' => result.push('\u{c}'),
+            'n' => result.push('\n'),
+            'r' => result.push('\r'),
+            't' => result.push('\t'),
+            'u' => {
+                let code: String = chars.by_ref().take(4).collect();
+                result.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+            },
+            x => result.push(x),
+        }
+    }
+    Some(result)
+}
+
 pub struct MyParser;
 
-type ExpressionParseResult = Option<Rc<RefCell<ASTExpression>>>;
+type ExpressionParseResult = Option<Arc<RwLock<ASTExpression>>>;
 
 impl MyParser {
     pub fn parse_expression(&self, pair: Pair<Rule>) -> ExpressionParseResult {
@@ -29,6 +60,10 @@ impl MyParser {
             primary_expression => self.parse_primary_expression(pair),
             and_expression => self.parse_and_expression(pair),
             or_expression => self.parse_or_expression(pair),
+            not_expression => self.parse_not_expression(pair),
+            optional_expression => self.parse_optional_expression(pair),
+            aggregate_expression => self.parse_aggregate_expression(pair),
+            comparison_expression => self.parse_comparison_expression(pair),
             expression => self.parse_expression(pair.into_inner().next().unwrap()),
             value => self.parse_value(pair),
             _ => {
@@ -45,12 +80,12 @@ impl MyParser {
             let left = self.parse_expression(pairs[0].clone())?;
             let right = self.parse_expression(pairs[1].clone())?;
             let or_expression = ASTOrExpression::new(left, right);
-            let mut ast = Rc::new(RefCell::new(ASTExpression::from_or_expression(or_expression)));
+            let mut ast = Arc::new(RwLock::new(ASTExpression::from_or_expression(or_expression)));
 
-            for i in 2..pairs.len() {
-                let expression = self.parse_expression(pairs[i].clone())?;
+            for pair in pairs.iter().skip(2) {
+                let expression = self.parse_expression(pair.clone())?;
                 let or_expression = ASTOrExpression::new(ast.clone(), expression);
-                ast = Rc::new(RefCell::new(ASTExpression::from_or_expression(or_expression)));
+                ast = Arc::new(RwLock::new(ASTExpression::from_or_expression(or_expression)));
             }
             Some(ast)
         }
@@ -64,17 +99,132 @@ impl MyParser {
             let left = self.parse_expression(pairs[0].clone())?;
             let right = self.parse_expression(pairs[1].clone())?;
             let and_expression = ASTAndExpression::new(left, right);
-            let mut ast = Rc::new(RefCell::new(ASTExpression::from_and_expression(and_expression)));
+            let mut ast = Arc::new(RwLock::new(ASTExpression::from_and_expression(and_expression)));
 
-            for i in 2..pairs.len() {
-                let expression = self.parse_expression(pairs[i].clone())?;
+            for pair in pairs.iter().skip(2) {
+                let expression = self.parse_expression(pair.clone())?;
                 let and_expression = ASTAndExpression::new(ast.clone(), expression);
-                ast = Rc::new(RefCell::new(ASTExpression::from_and_expression(and_expression)));
+                ast = Arc::new(RwLock::new(ASTExpression::from_and_expression(and_expression)));
             }
             Some(ast)
         }
     }
 
+    pub fn parse_not_expression(&self, pair: Pair<Rule>) -> ExpressionParseResult {
+        // the optional `not` keyword is the only other inner pair
+        let p = pair.into_inner().last()?;
+        let expression = self.parse_expression(p)?;
+        let not_expression = ASTNotExpression::new(expression);
+        Some(Arc::new(RwLock::new(ASTExpression::from_not_expression(not_expression))))
+    }
+
+    pub fn parse_optional_expression(&self, pair: Pair<Rule>) -> ExpressionParseResult {
+        let p = pair.into_inner().last()?;
+        let expression = self.parse_expression(p)?;
+        let optional_expression = ASTOptionalExpression::new(expression);
+        Some(Arc::new(RwLock::new(ASTExpression::from_optional_expression(optional_expression))))
+    }
+
+    pub fn parse_aggregate_expression(&self, pair: Pair<Rule>) -> ExpressionParseResult {
+        let mut pairs = pair.into_inner();
+        let op = AggregateOp::from_name(pairs.next()?.as_str())?;
+        let result = Name::new(pairs.next()?.as_str());
+
+        let mut value = None;
+        let mut expression = None;
+        let mut group_by = Vec::new();
+        for p in pairs {
+            match p.as_rule() {
+                Rule::variable => value = Some(Name::new(p.as_str())),
+                Rule::expression => expression = Some(self.parse_expression(p)?),
+                Rule::group_by => {
+                    group_by = p.into_inner().skip(1).map(|x| Name::new(x.as_str())).collect();
+                },
+                _ => {}
+            }
+        }
+
+        let aggregate_expression = ASTAggregateExpression {
+            op,
+            result,
+            value,
+            expression: expression?,
+            group_by,
+        };
+        Some(Arc::new(RwLock::new(ASTExpression::from_aggregate_expression(aggregate_expression))))
+    }
+
+    /// Lower `?a * 2 > ?b` to the built-in relations `mul ?a 2 ?#n && gt ?#n ?b`,
+    /// where `?#n` is a hidden variable holding an intermediate result
+    pub fn parse_comparison_expression(&self, pair: Pair<Rule>) -> ExpressionParseResult {
+        let pairs: Vec<Pair<Rule>> = pair.into_inner().collect();
+        let mut conjuncts = Vec::new();
+
+        let left = self.lower_arithmetic_expression(pairs[0].clone(), &mut conjuncts)?;
+        let right = self.lower_arithmetic_expression(pairs[2].clone(), &mut conjuncts)?;
+        let relation = match pairs[1].as_str() {
+            ">=" => "ge",
+            "<=" => "le",
+            "==" | "=" => "eq",
+            "!=" => "neq",
+            ">" => "gt",
+            "<" => "lt",
+            _ => return None,
+        };
+        conjuncts.push(Self::relation(relation, vec![left, right]));
+
+        let mut conjuncts = conjuncts.into_iter();
+        let mut ast = conjuncts.next()?;
+        for expression in conjuncts {
+            let and_expression = ASTAndExpression::new(ast, expression);
+            ast = Arc::new(RwLock::new(ASTExpression::from_and_expression(and_expression)));
+        }
+        Some(ast)
+    }
+
+    /// Returns the value or variable holding the result of an arithmetic expression,
+    /// the relations computing it are appended to `conjuncts`
+    fn lower_arithmetic_expression(&self, pair: Pair<Rule>, conjuncts: &mut Vec<Arc<RwLock<ASTExpression>>>) -> ExpressionParseResult {
+        use Rule::*;
+        match pair.as_rule() {
+            arithmetic_expression | arithmetic_term => {
+                let pairs: Vec<Pair<Rule>> = pair.into_inner().collect();
+                let mut result = self.lower_arithmetic_expression(pairs[0].clone(), conjuncts)?;
+
+                for chunk in pairs[1..].chunks(2) {
+                    let op = &chunk[0];
+                    let operand = self.lower_arithmetic_expression(chunk.get(1)?.clone(), conjuncts)?;
+                    let relation = match op.as_str() {
+                        "+" => "add",
+                        "-" => "sub",
+                        "*" => "mul",
+                        "/" => "div",
+                        _ => return None,
+                    };
+
+                    // the position of the operator makes the name unique within the query
+                    let name = format!("{}{}", HIDDEN_VARIABLE_PREFIX, op.as_span().start());
+                    let temp = Arc::new(RwLock::new(ASTExpression::from_variable(&name, false)));
+                    conjuncts.push(Self::relation(relation, vec![result, operand, temp]));
+                    result = Arc::new(RwLock::new(ASTExpression::from_variable(&name, false)));
+                }
+                Some(result)
+            },
+            arithmetic_factor => self.lower_arithmetic_expression(pair.into_inner().next()?, conjuncts),
+            value => self.parse_value(pair),
+            _ => None
+        }
+    }
+
+    fn relation(name: &str, args: Vec<Arc<RwLock<ASTExpression>>>) -> Arc<RwLock<ASTExpression>> {
+        let ast = ASTPrimaryExpression {
+            predicate: String::from(name),
+            path: Vec::new(),
+            args
+        };
+        Arc::new(RwLock::new(ASTExpression::from_primary_expression(ast)))
+    }
+
     pub fn parse_value(&self, pair: Pair<Rule>) -> ExpressionParseResult {
         let p = pair.into_inner().next()?;
         let rule = p.as_rule();
@@ -84,27 +234,73 @@ impl MyParser {
             number => self.parse_number(p),
             string => self.parse_string(p),
             variable => self.parse_variable(p),
+            path_variable => self.parse_path_variable(p),
+            object_pattern => self.parse_object_pattern(p),
             _ => None
         }
     }
 
+    pub fn parse_object_pattern(&self, pair: Pair<Rule>) -> ExpressionParseResult {
+        let mut fields = Vec::new();
+        for field in pair.into_inner() {
+            let mut pairs = field.into_inner();
+            let key = pairs.next()?.into_inner().next()?;
+            let key = match key.as_rule() {
+                Rule::string => unescape(key.into_inner().next()?.as_str())?,
+                _ => String::from(key.as_str()),
+            };
+            let value = self.parse_value(pairs.next()?)?;
+            fields.push((key, value));
+        }
+
+        Some(Arc::new(RwLock::new(
+            ASTExpression::from_object_pattern(ASTObjectPattern { fields })
+        )))
+    }
+
     pub fn parse_variable(&self, pair: Pair<Rule>) -> ExpressionParseResult {
         let s = pair.as_str();
-        Some(Rc::new(RefCell::new(
+        Some(Arc::new(RwLock::new(
             ASTExpression::from_variable(s, false)
         )))
     }
 
+    pub fn parse_path_variable(&self, pair: Pair<Rule>) -> ExpressionParseResult {
+        let mut pairs = pair.into_inner();
+        let name = pairs.next()?.as_str();
+        let path = pairs.map(|x| self.parse_accessor(x)).collect::<Option<Vec<_>>>()?;
+        Some(Arc::new(RwLock::new(
+            ASTExpression::from_path_variable(name, path)
+        )))
+    }
+
+    pub fn parse_accessor(&self, pair: Pair<Rule>) -> Option<Accessor> {
+        let rule = pair.as_rule();
+        let p = pair.into_inner().next()?;
+
+        use Rule::*;
+        match rule {
+            field_accessor => Some(Accessor::Field(String::from(p.as_str()))),
+            index_accessor => Some(Accessor::Index(p.as_str().parse().ok()?)),
+            wildcard_accessor => Some(Accessor::Wildcard),
+            _ => None
+        }
+    }
+
     pub fn parse_string(&self, pair: Pair<Rule>) -> ExpressionParseResult {
-        let s = pair.into_inner().next()?.as_str();
-        Some(Rc::new(RefCell::new(
-            ASTExpression::from_str(s)
+        let s = unescape(pair.into_inner().next()?.as_str())?;
+        Some(Arc::new(RwLock::new(
+            ASTExpression::from_string(&s)
         )))
     }
 
     pub fn parse_number(&self, pair: Pair<Rule>) -> ExpressionParseResult {
-        let number = pair.as_str().parse::<f64>().ok()?;
-        Some(Rc::new(RefCell::new(ASTExpression::from_number(number))))
+        // integers are kept exact, rather than read as floats
+        let number = match pair.as_str().parse::<i128>() {
+            Ok(x) => ValueType::Int(x),
+            Err(_) => ValueType::Float(pair.as_str().parse::<f64>().ok()?),
+        };
+        Some(Arc::new(RwLock::new(ASTExpression::from_number(number))))
     }
 
     pub fn parse_primary_expression(&self, pair: Pair<Rule>) -> ExpressionParseResult {
@@ -114,26 +310,176 @@ impl MyParser {
             // an expression
             self.parse_expression(pairs[0].clone())
         } else {
-            let predicate = pairs[0].as_str();
-            let predicate: Vec<String> = predicate.split(".").map(|x| String::from(x)).collect();
+            let mut predicate_pairs = pairs[0].clone().into_inner();
+            let predicate = String::from(predicate_pairs.next()?.as_str());
+            let path = predicate_pairs.map(|x| self.parse_accessor(x)).collect::<Option<Vec<_>>>()?;
 
             let mut args = Vec::new();
-            for i in 1..pairs.len() {
-                let expr = self.parse_expression(pairs[i].clone())?;
+            for pair in pairs.iter().skip(1) {
+                let expr = self.parse_expression(pair.clone())?;
                 args.push(expr);
             }
 
             let ast = ASTPrimaryExpression {
                 predicate,
+                path,
                 args
             };
             let ast = ASTExpression::from_primary_expression(ast);
-            Some(Rc::new(RefCell::new(ast)))
+            Some(Arc::new(RwLock::new(ast)))
         }
     }
 
+    pub fn parse_rule_definition(&self, pair: Pair<Rule>) -> Option<ASTRuleDefinition> {
+        let pairs: Vec<Pair<Rule>> = pair.into_inner().collect();
+        let (body, head) = pairs.split_last()?;
+
+        let name = String::from(head.first()?.as_str());
+        let params = head.iter().skip(1).map(|x| String::from(x.as_str())).collect();
+        let body = self.parse_expression(body.clone())?;
+
+        Some(ASTRuleDefinition {
+            name,
+            params,
+            body
+        })
+    }
+
+    pub fn parse_program(&self, pair: Pair<Rule>) -> Option<ASTProgram> {
+        let mut rules = Vec::new();
+        let mut expression = None;
+        let mut select = None;
+        let mut modifiers = ASTModifiers::default();
+
+        for p in pair.into_inner() {
+            match p.as_rule() {
+                Rule::rule_definition => rules.push(self.parse_rule_definition(p)?),
+                Rule::select_clause => {
+                    let variables = p.into_inner().filter(|x| x.as_rule() == Rule::variable);
+                    select = Some(variables.map(|x| String::from(x.as_str())).collect());
+                },
+                Rule::expression => expression = Some(self.parse_expression(p)?),
+                Rule::distinct_keyword => modifiers.distinct = true,
+                Rule::order_by_clause => {
+                    for key in p.into_inner().filter(|x| x.as_rule() == Rule::order_key) {
+                        let mut pairs = key.into_inner();
+                        let variable = String::from(pairs.next()?.as_str());
+                        let descending = pairs.next().is_some_and(|x| x.as_rule() == Rule::desc_keyword);
+                        modifiers.order_by.push(ASTOrderKey { variable, descending });
+                    }
+                },
+                Rule::limit_clause => modifiers.limit = Some(p.into_inner().last()?.as_str().parse().ok()?),
+                Rule::offset_clause => modifiers.offset = Some(p.into_inner().last()?.as_str().parse().ok()?),
+                _ => {}
+            }
+        }
+
+        Some(ASTProgram {
+            rules,
+            expression,
+            select,
+            modifiers,
+        })
+    }
+
     pub fn parsestring_expression(&self, s: &str) -> ExpressionParseResult {
         let pair = GDPParser::parse(Rule::expression, s).ok()?.next()?;
         self.parse_expression(pair)
     }
-}
\ No newline at end of file
+
+    pub fn parsestring_program(&self, s: &str) -> Option<ASTProgram> {
+        let pair = GDPParser::parse(Rule::program, s).ok()?.next()?;
+        self.parse_program(pair)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::node::ast_expression::ExpressionType;
+
+    /// The string arguments of a query with a single predicate
+    fn string_args(query: &str) -> Vec<String> {
+        let ast = MyParser.parsestring_expression(query).unwrap();
+        let ast = ast.read();
+        let p = match &ast.ast_type {
+            ExpressionType::PrimaryExpression(p) => p,
+            _ => panic!("not a primary expression"),
+        };
+        p.args.iter().filter_map(|arg| match &arg.read().ast_type {
+            ExpressionType::String(s) => Some(s.value.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert_eq!(unescape(r"plain").unwrap(), "plain");
+        assert_eq!(unescape(r"\\w\/").unwrap(), r"\w/");
+        assert_eq!(unescape(r#"\"quoted\""#).unwrap(), "\"quoted\"");
+        assert_eq!(unescape(r"a\nb\tc\r\b\f").unwrap(), "a\nb\tc\r\u{8}\u{c}");
+        assert_eq!(unescape(r"\u7965\u793c").unwrap(), "祥礼");
+        assert_eq!(unescape(r"\uzzzz"), None);
+    }
+
+    #[test]
+    fn string_literals_are_unescaped() {
+        assert_eq!(string_args(r#"regex "UI_EquipIcon_(\\w+)" ?icon ?name"#), vec![r"UI_EquipIcon_(\w+)"]);
+        assert_eq!(string_args(r#"CHS ?x "a \"b\" c""#), vec![r#"a "b" c"#]);
+        assert_eq!(string_args(r#"CHS ?x "祭礼剑""#), vec!["祭礼剑"]);
+    }
+
+    #[test]
+    fn pattern_keys_are_unescaped() {
+        let ast = MyParser.parsestring_expression(r#"Data ?x {"a\\b": ?y}"#).unwrap();
+        let ast = ast.read();
+        let p = match &ast.ast_type {
+            ExpressionType::PrimaryExpression(p) => p,
+            _ => panic!("not a primary expression"),
+        };
+        let pattern = p.args[1].read();
+        match &pattern.ast_type {
+            ExpressionType::ObjectPattern(o) => assert_eq!(o.fields[0].0, r"a\b"),
+            _ => panic!("not an object pattern"),
+        }
+    }
+
+    #[test]
+    fn rule_definitions() {
+        let program = MyParser.parsestring_program("rule (name ?w ?n) { A.name ?w ?n } rule (none) { B ?x } name ?x ?y").unwrap();
+        assert_eq!(program.rules.len(), 2);
+        assert_eq!(program.rules[0].name, "name");
+        assert_eq!(program.rules[0].params, vec!["?w", "?n"]);
+        assert_eq!(program.rules[1].name, "none");
+        assert!(program.rules[1].params.is_empty());
+        assert!(program.expression.is_some());
+
+        let program = MyParser.parsestring_program("rule (name ?w) { A ?w }").unwrap();
+        assert_eq!(program.rules.len(), 1);
+        assert!(program.expression.is_none());
+
+        assert!(MyParser.parsestring_program("rule name ?w { A ?w }").is_none());
+        assert!(MyParser.parsestring_program("rule (name ?w) { A ?w } rule (other ?w) { B ?w } && C ?x").is_none());
+    }
+    #[test]
+    fn distinct_follows_the_query() {
+        let program = MyParser.parsestring_program("select ?x where A ?x distinct limit 1").unwrap();
+        assert_eq!(program.select, Some(vec![String::from("?x")]));
+        assert!(program.modifiers.distinct);
+        assert_eq!(program.modifiers.limit, Some(1));
+
+        assert!(!MyParser.parsestring_program("select ?x where A ?x").unwrap().modifiers.distinct);
+        assert!(MyParser.parsestring_program("select distinct ?x where A ?x").is_none());
+    }
+
+    #[test]
+    fn comparisons_lower_to_relations() {
+        let lowered = |q: &str| MyParser.parsestring_expression(q).unwrap().read().to_string();
+        assert_eq!(lowered("?rank >= 4"), "ge ?rank 4");
+        assert_eq!(lowered("?a = ?b"), "eq ?a ?b");
+        // `*` binds tighter than `+`, and intermediate results are held by hidden variables named after the operator
+        assert_eq!(lowered("?a + ?b * 2 != ?c"), "mul ?b 2 ?#8 && add ?a ?#8 ?#3 && neq ?#3 ?c");
+        assert_eq!(lowered("(?a + ?b) * 2 < 10"), "add ?a ?b ?#4 && mul ?#4 2 ?#10 && lt ?#10 10");
+        assert_eq!(lowered("?a - 1 - 1 <= ?a / 2"), "sub ?a 1 ?#3 && sub ?#3 1 ?#7 && div ?a 2 ?#17 && le ?#7 ?#17");
+        assert!(MyParser.parsestring_expression("?a + 1").is_none());
+    }
+}
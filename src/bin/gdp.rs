@@ -19,13 +19,10 @@
 use clap::Parser;
 use std::env::current_dir;
 use std::io::{stdout, Write};
-use gdp::ast::parser::{MyParser, parse};
 use gdp::file_system::cached_file_system::CachedFileSystem;
 use gdp::file_system::http_file_system::HttpFileSystem;
-use gdp::file_system::naive_file_system::NaiveFileSystem;
-use gdp::query::generic_query::GenericQueries;
 use gdp::query::query::QueryProgram;
-use gdp::runtime::frame::Frame;
+use gdp::query::result::{to_serde_row, QueryRows};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -45,7 +42,7 @@ fn main() {
 
     let args: Args = Args::parse();
 
-    let path = if args.path == "." {
+    let _path = if args.path == "." {
         current_dir().unwrap()
     } else {
         current_dir().unwrap().join(args.path)
@@ -53,28 +50,120 @@ fn main() {
     // let fs1 = NaiveFileSystem::new(path);
     let fs1 = HttpFileSystem::new("https://genshin-data.uigf.org/d/latest/");
     let fs2 = CachedFileSystem::new(Box::new(fs1));
-    let p = QueryProgram {
-        generic_query: GenericQueries::default(),
-        file_system: Box::new(fs2)
-    };
+    let p = QueryProgram::new(Box::new(fs2));
     // WeaponExcelConfigData ?x && WeaponExcelConfigData.nameTextMapHash ?x ?y && CHS ?y "祭礼剑"
     // AvatarExcelConfigData.nameTextMapHash ?x ?y && CHS ?y "可莉"
 
     loop {
         print!(">>> ");
-        stdout().flush();
+        stdout().flush().unwrap();
         let mut s = String::new();
-        std::io::stdin().read_line(&mut s);
+        std::io::stdin().read_line(&mut s).unwrap();
         let s = s.trim();
 
         if s == "q" || s == "quit" || s == "exit" || s == "exit()" || s == "quit()" {
             break;
         }
 
-        let result = p.query(&s);
-        let result = result.unwrap_or(vec![]);
-        let result: Vec<_> = result.into_iter().filter(|x| x.is_resolved()).map(|x| x.to_serde_map()).collect();
+        if let Some(q) = s.strip_prefix("explain ") {
+            match p.explain(q) {
+                Ok(Some(plan)) => print!("{}", plan),
+                Ok(None) => {},
+                Err(e) => println!("error: {}", e),
+            }
+            continue;
+        }
+
+        if let Some(q) = s.strip_prefix("profile ") {
+            match p.profile(q) {
+                Ok((result, profile)) => {
+                    println!("{}", serde_json::to_string_pretty(&result.to_serde_rows()).unwrap());
+                    print!("{}", profile);
+                },
+                Err(e) => println!("error: {}", e),
+            }
+            continue;
+        }
+
+        let rows = match p.query_iter(s) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        print_rows(rows, &mut stdout()).unwrap();
+    }
+}
+
+/// Print each answer as soon as it is found, as an element of a JSON array
+fn print_rows(rows: QueryRows, out: &mut impl Write) -> std::io::Result<()> {
+    let columns = rows.columns.clone();
+    let mut count = 0;
+    write!(out, "[")?;
+    for frame in rows {
+        let row = serde_json::to_string_pretty(&to_serde_row(&frame, &columns)).unwrap();
+        let separator = if count == 0 { "" } else { "," };
+        write!(out, "{}\n  {}", separator, row.replace('\n', "\n  "))?;
+        out.flush()?;
+        count += 1;
+    }
+    writeln!(out, "{}]", if count == 0 { "" } else { "\n" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use gdp::runtime::frame::Frame;
+    use gdp::runtime::value::Value;
+
+    /// Output shared with the test, which can read what was printed so far
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(x: i128) -> Frame {
+        let mut frame = Frame::new();
+        frame.add("?x", Value::from_int(x));
+        frame
+    }
+
+    #[test]
+    fn rows_are_printed_as_they_are_found() {
+        let output = SharedOutput::default();
+        let printed = output.clone();
+        // each answer is computed only after the previous one is printed
+        let frames = (0..3).map(move |i| {
+            assert_eq!(printed.text().matches("\"?x\"").count(), i as usize);
+            frame(i)
+        });
+        let rows = QueryRows::new(vec![String::from("?x")], Box::new(frames));
+        print_rows(rows, &mut output.clone()).unwrap();
+        assert_eq!(output.text(), "[\n  {\n    \"?x\": 0\n  },\n  {\n    \"?x\": 1\n  },\n  {\n    \"?x\": 2\n  }\n]\n");
+    }
 
-        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    #[test]
+    fn no_rows_print_an_empty_array() {
+        let output = SharedOutput::default();
+        let rows = QueryRows::new(vec![String::from("?x")], Box::new(std::iter::empty()));
+        print_rows(rows, &mut output.clone()).unwrap();
+        assert_eq!(output.text(), "[]\n");
     }
 }
@@ -1,20 +1,21 @@
-use std::cell::RefCell;
+use parking_lot::RwLock;
 use std::collections::HashMap;
 use serde_json::Value;
 use crate::file_system::file_system::FileSystem;
 
+/// Keeps the contents of files in memory, until the version of a file changes in the upper file system
 pub struct CachedFileSystem {
     pub upper_file_system: Box<dyn FileSystem>,
-    pub cache: RefCell<HashMap<String, String>>,
-    pub cache_serde: RefCell<HashMap<String, serde_json::Value>>,
+    pub cache: RwLock<HashMap<String, (Option<u64>, String)>>,
+    pub cache_serde: RwLock<HashMap<String, (Option<u64>, serde_json::Value)>>,
 }
 
 impl CachedFileSystem {
     pub fn new (fs1: Box<dyn FileSystem>) -> Self {
         CachedFileSystem {
             upper_file_system: fs1,
-            cache: RefCell::new(HashMap::new()),
-            cache_serde: RefCell::new(HashMap::new()),
+            cache: RwLock::new(HashMap::new()),
+            cache_serde: RwLock::new(HashMap::new()),
         }
     }
 }
@@ -25,21 +26,31 @@ impl FileSystem for CachedFileSystem {
     }
 
     fn read(&self, path: &str) -> Option<String> {
-        if self.cache.borrow().contains_key(path) {
-            let v = self.cache.borrow().get(path).unwrap().clone();
-            Some(v)
-        } else {
-            let value = self.upper_file_system.read(path)?;
-            let mut handle = self.cache.borrow_mut();
-            handle.insert(path.to_string(), value.clone());
-            Some(value)
+        let version = self.upper_file_system.version(path);
+        if let Some((v, content)) = self.cache.read().get(path) {
+            if *v == version {
+                return Some(content.clone());
+            }
         }
+
+        let value = self.upper_file_system.read(path)?;
+        let mut handle = self.cache.write();
+        handle.insert(path.to_string(), (version, value.clone()));
+        Some(value)
+    }
+
+    fn read_uncached(&self, path: &str) -> Option<String> {
+        self.upper_file_system.read_uncached(path)
     }
 
     fn read_serde(&self, path: &str) -> Option<Value> {
-        if self.cache_serde.borrow().contains_key(path) {
-            let v = self.cache_serde.borrow().get(path).unwrap().clone();
-            Some(v)
+        let version = self.upper_file_system.version(path);
+        let cached = match self.cache_serde.read().get(path) {
+            Some((v, content)) if *v == version => Some(content.clone()),
+            _ => None,
+        };
+        if cached.is_some() {
+            cached
         } else {
             let s = self.read(path)?;
             let mut parsed: serde_json::Value = serde_json::from_str(&s).ok()?;
@@ -65,9 +76,17 @@ impl FileSystem for CachedFileSystem {
                 }
             }
 
-            let mut handle = self.cache_serde.borrow_mut();
-            handle.insert(path.to_string(), parsed.clone());
+            let mut handle = self.cache_serde.write();
+            handle.insert(path.to_string(), (version, parsed.clone()));
             Some(parsed)
         }
     }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        self.upper_file_system.size(path)
+    }
+
+    fn version(&self, path: &str) -> Option<u64> {
+        self.upper_file_system.version(path)
+    }
 }
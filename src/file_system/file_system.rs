@@ -1,7 +1,23 @@
-pub trait FileSystem {
+pub trait FileSystem: Send + Sync {
     fn exists(&self, path: &str) -> bool;
 
     fn read(&self, path: &str) -> Option<String>;
 
+    /// Read a file whose contents the caller keeps itself, so that layers caching contents do not keep a copy
+    fn read_uncached(&self, path: &str) -> Option<String> {
+        self.read(path)
+    }
+
     fn read_serde(&self, path: &str) -> Option<serde_json::Value>;
+
+    /// The length of the file at `path` in bytes, `None` if the file system cannot tell without reading the file
+    fn size(&self, _path: &str) -> Option<u64> {
+        None
+    }
+
+    /// A token that changes whenever the file at `path` changes, `None` if the file system cannot tell,
+    /// in which case the file is assumed to never change
+    fn version(&self, _path: &str) -> Option<u64> {
+        None
+    }
 }
@@ -34,7 +34,7 @@ impl FileSystem for HttpFileSystem {
                 return false;
             }
         }
-        return true;
+        true
     }
 
     fn read(&self, path: &str) -> Option<String> {
@@ -54,7 +54,7 @@ impl FileSystem for HttpFileSystem {
             }
         }
         // println!("{}", j);
-        return Some(format!("{}", j));
+        Some(format!("{}", j))
     }
 
     fn read_serde(&self, path: &str) -> Option<Value> {
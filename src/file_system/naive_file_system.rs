@@ -1,4 +1,6 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::path::{PathBuf};
 use serde_json::Value;
 use crate::file_system::file_system::FileSystem;
@@ -23,12 +25,24 @@ impl FileSystem for NaiveFileSystem {
 
     fn read(&self, path: &str) -> Option<String> {
         let p = self.working_dir.join(path);
-        let s = fs::read_to_string(p).ok();
-        s
+        fs::read_to_string(p).ok()
     }
 
     fn read_serde(&self, path: &str) -> Option<Value> {
         let s = self.read(path)?;
         serde_json::from_str(&s).ok()
     }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        fs::metadata(self.working_dir.join(path)).ok().map(|x| x.len())
+    }
+
+    /// The modification time and the size of the file
+    fn version(&self, path: &str) -> Option<u64> {
+        let metadata = fs::metadata(self.working_dir.join(path)).ok()?;
+        let mut hasher = DefaultHasher::new();
+        metadata.modified().ok()?.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        Some(hasher.finish())
+    }
 }
@@ -1,3 +1,4 @@
+#![allow(clippy::module_inception)]
 #[macro_use] extern crate pest_derive;
 pub mod query;
 pub mod ast;
@@ -0,0 +1,65 @@
+use crate::ast::node::ast_expression::AggregateOp;
+use crate::runtime::value::Value;
+
+/// Running state of one group of an aggregate
+pub struct Accumulator {
+    op: AggregateOp,
+    count: usize,
+    sum: f64,
+    /// the exact sum, while every number added is an integer and it does not overflow
+    int_sum: Option<i128>,
+    best: Option<Value>,
+}
+
+impl Accumulator {
+    pub fn new(op: AggregateOp) -> Self {
+        Self {
+            op,
+            count: 0,
+            sum: 0.0,
+            int_sum: Some(0),
+            best: None,
+        }
+    }
+
+    /// Add one answer, whose aggregated variable is bound to `value`.
+    /// `count` counts every answer, the other aggregates skip answers without a number
+    pub fn add(&mut self, value: Option<&Value>) {
+        if self.op == AggregateOp::Count {
+            self.count += 1;
+            return;
+        }
+
+        let (value, n) = match value.and_then(|x| Some((x, x.as_number()?))) {
+            Some(x) => x,
+            None => return,
+        };
+        self.count += 1;
+        self.sum += n;
+        self.int_sum = self.int_sum.and_then(|s| s.checked_add(value.as_int()?));
+        let better = match (self.op, &self.best) {
+            (AggregateOp::Min, Some(b)) => value.compare_numbers(b).is_some_and(|x| x.is_lt()),
+            (AggregateOp::Max, Some(b)) => value.compare_numbers(b).is_some_and(|x| x.is_gt()),
+            _ => true,
+        };
+        if better {
+            self.best = Some(value.clone());
+        }
+    }
+
+    /// The result, `min`, `max` and `avg` of no numbers have none
+    pub fn result(&self) -> Option<Value> {
+        match self.op {
+            AggregateOp::Count => Some(Value::from_int(self.count as i128)),
+            AggregateOp::Sum => Some(self.int_sum.map(Value::from_int).unwrap_or_else(|| Value::from_number(self.sum))),
+            AggregateOp::Min | AggregateOp::Max => self.best.clone(),
+            AggregateOp::Avg => {
+                if self.count == 0 {
+                    None
+                } else {
+                    Some(Value::from_number(self.sum / self.count as f64))
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use crate::ast::node::ast_expression::{ASTExpression, ExpressionType};
+use crate::query::error::QueryError;
+use crate::query::generic_query::GenericQueries;
+use crate::query::rule::QueryRules;
+
+/// Checks which variables are bound at each point of a query, following the left to right evaluation order of `&&`.
+/// Calls into rules are checked with the binding pattern of their arguments, a call must give a rule as many
+/// arguments as it has parameters, and the rule's body must bind every parameter that the call leaves unbound.
+///
+/// A variable that only appears inside a negation is local to it (`!next ?a ?b` means there is no `?b`),
+/// but a variable that the negation shares with the rest of the query must be bound before the negation
+pub struct BindingChecker<'a> {
+    rules: &'a QueryRules,
+    generic_query: &'a GenericQueries,
+    /// rule calls that are checked or being checked, as a rule name and a pattern of bound arguments
+    checked: HashSet<String>,
+}
+
+impl<'a> BindingChecker<'a> {
+    pub fn new(rules: &'a QueryRules, generic_query: &'a GenericQueries) -> Self {
+        Self {
+            rules,
+            generic_query,
+            checked: HashSet::new(),
+        }
+    }
+
+    /// Check a query, returns the variables bound after it
+    pub fn check(&mut self, ast: &ASTExpression) -> Result<HashSet<String>, QueryError> {
+        let scope = Scope {
+            root: ast,
+            params: &[],
+            rule: None,
+        };
+        self.check_expression(ast, &scope, &HashSet::new())
+    }
+
+    /// Check `ast`, a part of `root`, which is a query or the body of the rule `rule` with `params`.
+    /// Returns the variables bound after `ast`, if it can run with `bound` variables bound beforehand
+    pub fn check_part(&mut self, ast: &ASTExpression, root: &ASTExpression, params: &[String], rule: Option<&String>,
+                      bound: &HashSet<String>) -> Result<HashSet<String>, QueryError> {
+        let scope = Scope {
+            root,
+            params,
+            rule,
+        };
+        self.check_expression(ast, &scope, bound)
+    }
+
+    /// Check `ast` with `bound` variables bound beforehand, returns the variables bound afterwards
+    fn check_expression(&mut self, ast: &ASTExpression, scope: &Scope, bound: &HashSet<String>) -> Result<HashSet<String>, QueryError> {
+        match &ast.ast_type {
+            ExpressionType::PrimaryExpression(p) => {
+                let name = p.predicate.as_str();
+                for arg in p.args.iter() {
+                    if let ExpressionType::Variable(x) = &arg.read().ast_type {
+                        if x.is_path && !bound.contains(x.name.as_str()) {
+                            return Err(QueryError::UnboundPath {
+                                variable: x.name.to_string(),
+                                rule: scope.rule.cloned(),
+                            });
+                        }
+                    }
+                }
+
+                let pattern: Vec<bool> = p.args.iter().map(|arg| {
+                    match arg.read().get_var_name() {
+                        Some(var_name) => bound.contains(var_name.as_str()),
+                        None => true,
+                    }
+                }).collect();
+
+                if self.rules.contains(name) {
+                    // the arguments are bound after the call, as the rule's body binds every parameter
+                    self.check_rule_call(name, &pattern)?;
+                } else if let Some(generic_query) = self.generic_query.get(name) {
+                    if !generic_query.can_evaluate(&pattern) {
+                        return Err(QueryError::InsufficientlyBound {
+                            predicate: String::from(name),
+                            rule: scope.rule.cloned(),
+                        });
+                    }
+                }
+
+                let mut result = bound.clone();
+                result.extend(ast.variables());
+                Ok(result)
+            },
+            ExpressionType::AndExpression(a) => {
+                let left = self.check_expression(&a.left.read(), scope, bound)?;
+                self.check_expression(&a.right.read(), scope, &left)
+            },
+            ExpressionType::OrExpression(a) => {
+                let left = self.check_expression(&a.left.read(), scope, bound)?;
+                let right = self.check_expression(&a.right.read(), scope, bound)?;
+                Ok(left.intersection(&right).cloned().collect())
+            },
+            ExpressionType::NotExpression(n) => {
+                let outside = scope.root.variables_except(Some(ast));
+                let inner = n.expression.read();
+                for variable in inner.variables() {
+                    let shared = outside.contains(&variable) || scope.params.contains(&variable);
+                    if shared && !bound.contains(&variable) {
+                        return Err(QueryError::UnboundInNegation {
+                            variable,
+                            rule: scope.rule.cloned(),
+                        });
+                    }
+                }
+                self.check_expression(&inner, scope, bound)?;
+                Ok(bound.clone())
+            },
+            ExpressionType::OptionalExpression(o) => self.check_expression(&o.expression.read(), scope, bound),
+            ExpressionType::AggregateExpression(a) => {
+                self.check_expression(&a.expression.read(), scope, bound)?;
+                let mut result = bound.clone();
+                result.insert(a.result.to_string());
+                result.extend(a.group_by.iter().map(|x| x.to_string()));
+                Ok(result)
+            },
+            _ => Ok(bound.clone()),
+        }
+    }
+}
+
+impl BindingChecker<'_> {
+    /// Check every clause of the rule `name` called with the arguments marked in `pattern` bound.
+    /// A recursive call is checked once, while the clauses calling it are being checked
+    fn check_rule_call(&mut self, name: &str, pattern: &[bool]) -> Result<(), QueryError> {
+        if !self.checked.insert(format!("{}{:?}", name, pattern)) {
+            return Ok(());
+        }
+
+        for clause in self.rules.get(name).unwrap_or_default().iter() {
+            if clause.params.len() != pattern.len() {
+                return Err(QueryError::RuleArity {
+                    rule: clause.name.clone(),
+                    expected: clause.params.len(),
+                    found: pattern.len(),
+                });
+            }
+
+            let inner_bound = clause.params.iter()
+                .zip(pattern.iter())
+                .filter(|(_, x)| **x)
+                .map(|(param, _)| param.clone())
+                .collect();
+            let body = clause.plan.read();
+            let inner_scope = Scope {
+                root: &body,
+                params: &clause.params,
+                rule: Some(&clause.name),
+            };
+            let after = self.check_expression(&body, &inner_scope, &inner_bound)?;
+            if let Some(param) = clause.params.iter().find(|x| !after.contains(*x)) {
+                return Err(QueryError::UnboundRuleParameter {
+                    rule: clause.name.clone(),
+                    parameter: param.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The query or rule body that an expression belongs to
+struct Scope<'b> {
+    root: &'b ASTExpression,
+    params: &'b [String],
+    rule: Option<&'b String>,
+}
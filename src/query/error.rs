@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone)]
+pub enum QueryError {
+    /// the query text does not match the grammar
+    Syntax,
+    /// a negation shares a variable with the rest of the query (or rule body), but no earlier part binds it
+    UnboundInNegation { variable: String, rule: Option<String> },
+    /// a built-in query is used before enough of its arguments are bound, for example `gt ?x 1` with `?x` unbound
+    InsufficientlyBound { predicate: String, rule: Option<String> },
+    /// a path like `?x.id` is used before `?x` is bound
+    UnboundPath { variable: String, rule: Option<String> },
+    /// a rule negates a rule that depends on itself, so the negation has no well defined meaning
+    UnstratifiedNegation { rule: String, negated: String },
+    /// `select` or `order by` lists a variable that the query does not use
+    UnknownVariable { variable: String },
+    /// a rule is called with a different number of arguments than it has parameters
+    RuleArity { rule: String, expected: usize, found: usize },
+    /// a rule's body does not bind one of its parameters, which the call leaves unbound
+    UnboundRuleParameter { rule: String, parameter: String },
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::Syntax => write!(f, "syntax error"),
+            QueryError::UnboundInNegation { variable, rule } => {
+                write!(f, "variable {} is used in a negation, but it is not bound before the negation", variable)?;
+                if let Some(r) = rule {
+                    write!(f, " (in rule {})", r)?;
+                }
+                Ok(())
+            },
+            QueryError::InsufficientlyBound { predicate, rule } => {
+                write!(f, "{} is used before enough of its arguments are bound", predicate)?;
+                if let Some(r) = rule {
+                    write!(f, " (in rule {})", r)?;
+                }
+                Ok(())
+            },
+            QueryError::UnboundPath { variable, rule } => {
+                write!(f, "variable {} is used in a path, but it is not bound before", variable)?;
+                if let Some(r) = rule {
+                    write!(f, " (in rule {})", r)?;
+                }
+                Ok(())
+            },
+            QueryError::UnstratifiedNegation { rule, negated } => {
+                write!(f, "rule {} negates {} (with `!`, `optional` or an aggregate), which recursively depends on {}", rule, negated, rule)
+            },
+            QueryError::UnknownVariable { variable } => {
+                write!(f, "variable {} is selected or ordered by, but the query does not use it", variable)
+            },
+            QueryError::RuleArity { rule, expected, found } => {
+                write!(f, "rule {} has {} parameters, but is called with {} arguments", rule, expected, found)
+            },
+            QueryError::UnboundRuleParameter { rule, parameter } => {
+                write!(f, "parameter {} of rule {} is not bound by its body, nor by the call", parameter, rule)
+            },
+        }
+    }
+}
+
+impl Error for QueryError {}
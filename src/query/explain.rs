@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use crate::ast::node::ast_expression::{ASTExpression, ExpressionType};
+
+/// Short name of a step, the whole expression for a predicate, or the operator of a compound expression
+pub fn step_label(ast: &ASTExpression) -> String {
+    match &ast.ast_type {
+        ExpressionType::AndExpression(_) => String::from("&&"),
+        ExpressionType::OrExpression(_) => String::from("||"),
+        ExpressionType::NotExpression(_) => String::from("!"),
+        ExpressionType::OptionalExpression(_) => String::from("optional"),
+        ExpressionType::AggregateExpression(a) => {
+            let mut label = format!("{} {}", a.op.name(), a.result);
+            if let Some(x) = &a.value {
+                label = format!("{} {}", label, x);
+            }
+            if !a.group_by.is_empty() {
+                label = format!("{} by {}", label, a.group_by.join(", "));
+            }
+            label
+        },
+        _ => ast.to_string(),
+    }
+}
+
+fn format_rows(rows: f64) -> String {
+    if rows < 10.0 {
+        format!("{:.1}", rows)
+    } else {
+        format!("{:.0}", rows)
+    }
+}
+
+/// One step of the plan of a query, as `explain` shows it
+#[derive(Debug, Clone)]
+pub struct PlanStep {
+    pub label: String,
+    /// how the step reads its data, for a predicate
+    pub access: Option<String>,
+    /// expected number of frames after this step
+    pub rows: f64,
+    /// the parts of a compound expression, in evaluation order
+    pub children: Vec<PlanStep>,
+}
+
+impl PlanStep {
+    fn write(&self, f: &mut Formatter<'_>, depth: usize) -> std::fmt::Result {
+        write!(f, "{}{}", "  ".repeat(depth), self.label)?;
+        match &self.access {
+            Some(access) => writeln!(f, "  [{}, ~{} rows]", access, format_rows(self.rows))?,
+            None => writeln!(f, "  [~{} rows]", format_rows(self.rows))?,
+        }
+        self.children.iter().try_for_each(|x| x.write(f, depth + 1))
+    }
+}
+
+impl Display for PlanStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+/// Counters of one step of a query, summed over every time it runs
+#[derive(Debug, Clone, Default)]
+pub struct StepProfile {
+    pub label: String,
+    /// nesting depth of the step in the query
+    pub depth: usize,
+    pub calls: usize,
+    pub input_frames: usize,
+    pub output_frames: usize,
+    /// rows of files and entries of text maps read by the step itself
+    pub rows_scanned: usize,
+    pub file_loads: usize,
+    /// wall time spent producing the frames of the step, including the steps it reads from
+    pub time: Duration,
+}
+
+/// Counters collected while a query runs with `profile`, for each step in the order the steps first ran
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub steps: Vec<StepProfile>,
+    /// index of each step, by the address of its expression
+    index: HashMap<usize, usize>,
+    /// the steps producing a frame now, innermost last
+    stack: Vec<usize>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a run of the step of `ast`, returns its index
+    pub fn step(&mut self, ast: &ASTExpression, depth: usize) -> usize {
+        let steps = &mut self.steps;
+        let index = *self.index.entry(ast as *const ASTExpression as usize).or_insert_with(|| {
+            steps.push(StepProfile {
+                label: step_label(ast),
+                depth,
+                ..StepProfile::default()
+            });
+            steps.len() - 1
+        });
+        self.steps[index].calls += 1;
+        index
+    }
+
+    /// Depth of the step producing a frame now, `None` outside of any step
+    pub fn running_depth(&self) -> Option<usize> {
+        self.stack.last().map(|x| self.steps[*x].depth)
+    }
+
+    /// Count a frame going into a step
+    pub fn input(&mut self, index: usize) {
+        self.steps[index].input_frames += 1;
+    }
+
+    /// A step starts producing a frame
+    pub fn enter(&mut self, index: usize) {
+        self.stack.push(index);
+    }
+
+    /// A step is done producing a frame, `produced` is false when it has no more frames
+    pub fn exit(&mut self, index: usize, produced: bool, time: Duration) {
+        self.stack.pop();
+        let step = &mut self.steps[index];
+        step.output_frames += produced as usize;
+        step.time += time;
+    }
+
+    /// Count rows read by the running step
+    pub fn scan(&mut self, rows: usize) {
+        if let Some(index) = self.stack.last() {
+            self.steps[*index].rows_scanned += rows;
+        }
+    }
+
+    /// Count a file read by the running step
+    pub fn load(&mut self) {
+        if let Some(index) = self.stack.last() {
+            self.steps[*index].file_loads += 1;
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:>6} {:>9} {:>9} {:>10} {:>5} {:>10}  step", "calls", "in", "out", "scanned", "loads", "time")?;
+        for step in self.steps.iter() {
+            writeln!(
+                f,
+                "{:>6} {:>9} {:>9} {:>10} {:>5} {:>8.3}ms  {}{}",
+                step.calls,
+                step.input_frames,
+                step.output_frames,
+                step.rows_scanned,
+                step.file_loads,
+                step.time.as_secs_f64() * 1000.0,
+                "  ".repeat(step.depth),
+                step.label
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use crate::query::generic_query::{GenericQuery, VarOrValue};
+use crate::query::stream::Frames;
+use crate::runtime::frame::Frame;
+use crate::runtime::value::Value as MyValue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl ArithmeticOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+            ArithmeticOp::Mul => "mul",
+            ArithmeticOp::Div => "div",
+        }
+    }
+
+    /// `a op b`, `None` when dividing by zero or when an operand is not a number.
+    /// Integers give an exact integer, unless it overflows or a division has a remainder
+    pub fn apply(&self, a: &MyValue, b: &MyValue) -> Option<MyValue> {
+        if let (Some(x), Some(y)) = (a.as_int(), b.as_int()) {
+            let exact = match self {
+                ArithmeticOp::Add => x.checked_add(y),
+                ArithmeticOp::Sub => x.checked_sub(y),
+                ArithmeticOp::Mul => x.checked_mul(y),
+                ArithmeticOp::Div => x.checked_rem(y).filter(|r| *r == 0).and_then(|_| x.checked_div(y)),
+            };
+            if let Some(result) = exact {
+                return Some(MyValue::from_int(result));
+            }
+        }
+
+        let (x, y) = (a.as_number()?, b.as_number()?);
+        let result = match self {
+            ArithmeticOp::Add => x + y,
+            ArithmeticOp::Sub => x - y,
+            ArithmeticOp::Mul => x * y,
+            ArithmeticOp::Div => if y == 0.0 { return None } else { x / y },
+        };
+        Some(MyValue::from_number(result))
+    }
+
+    /// Solve `a op ? = c` for the right operand
+    pub fn solve_right(&self, a: &MyValue, c: &MyValue) -> Option<MyValue> {
+        match self {
+            ArithmeticOp::Add => ArithmeticOp::Sub.apply(c, a),
+            ArithmeticOp::Sub => ArithmeticOp::Sub.apply(a, c),
+            ArithmeticOp::Mul => ArithmeticOp::Div.apply(c, a),
+            ArithmeticOp::Div => ArithmeticOp::Div.apply(a, c),
+        }
+    }
+
+    /// Solve `? op b = c` for the left operand
+    pub fn solve_left(&self, b: &MyValue, c: &MyValue) -> Option<MyValue> {
+        match self {
+            ArithmeticOp::Add => ArithmeticOp::Sub.apply(c, b),
+            ArithmeticOp::Sub => ArithmeticOp::Add.apply(c, b),
+            ArithmeticOp::Mul => ArithmeticOp::Div.apply(c, b),
+            ArithmeticOp::Div => if b.as_number()? == 0.0 { None } else { ArithmeticOp::Mul.apply(c, b) },
+        }
+    }
+}
+
+/// `add ?a ?b ?c` holds if `?a + ?b == ?c`, and so on for `sub`, `mul` and `div`.
+/// Any two of the arguments must be bound numbers, the third one is computed from them.
+/// When the third one is not unique (`mul ?a 0 0`) or does not exist (`div ?a 0 1`), there is no result
+pub struct Arithmetic {
+    pub op: ArithmeticOp,
+}
+
+impl Arithmetic {
+    pub fn new(op: ArithmeticOp) -> Self {
+        Self {
+            op
+        }
+    }
+
+    pub fn query_frame(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Frame> {
+        let number = |index: usize| args[index].as_value().filter(|x| x.as_number().is_some());
+        let (a, b, c) = (number(0), number(1), number(2));
+
+        let (index, value) = match (a, b, c) {
+            (Some(a), Some(b), Some(c)) => {
+                return if self.op.apply(a, b)? == *c {
+                    Some(frame.clone())
+                } else {
+                    None
+                };
+            },
+            (Some(a), Some(b), None) => (2, self.op.apply(a, b)?),
+            (Some(a), None, Some(c)) => (1, self.op.solve_right(a, c)?),
+            (None, Some(b), Some(c)) => (0, self.op.solve_left(b, c)?),
+            _ => return None,
+        };
+
+        let mut new_frame = frame.clone();
+        new_frame.add(args[index].get_var_name()?, value);
+        Some(new_frame)
+    }
+}
+
+impl GenericQuery for Arithmetic {
+    fn query<'a>(&'a self, frame: &Frame, args: &[VarOrValue]) -> Frames<'a> {
+        if args.len() != 3 {
+            return Box::new(std::iter::empty());
+        }
+
+        let new_args = args.iter().map(|x| x.match_in_frame(frame)).collect::<Vec<_>>();
+        Box::new(self.query_frame(frame, &new_args).into_iter())
+    }
+
+    fn can_evaluate(&self, bound: &[bool]) -> bool {
+        bound.iter().filter(|x| **x).count() >= 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(x: i128) -> MyValue {
+        MyValue::from_int(x)
+    }
+
+    #[test]
+    fn integers_are_exact() {
+        // 2^53 + 1 is the first integer a float cannot hold
+        let big = 9007199254740993;
+        assert_eq!(ArithmeticOp::Add.apply(&int(big), &int(1)).unwrap().as_int(), Some(big + 1));
+        assert_eq!(ArithmeticOp::Sub.apply(&int(big), &int(big - 1)).unwrap().as_int(), Some(1));
+        assert_eq!(ArithmeticOp::Mul.apply(&int(big), &int(3)).unwrap().as_int(), Some(big * 3));
+        assert_eq!(ArithmeticOp::Div.apply(&int(big * 3), &int(3)).unwrap().as_int(), Some(big));
+
+        // beyond 64 bits
+        let max = i64::MAX as i128;
+        assert_eq!(ArithmeticOp::Add.apply(&int(max), &int(1)).unwrap().as_int(), Some(max + 1));
+        let u_max = u64::MAX as i128;
+        assert_eq!(ArithmeticOp::Mul.apply(&int(u_max), &int(2)).unwrap().as_int(), Some(u_max * 2));
+        assert_eq!(ArithmeticOp::Sub.apply(&int(-max), &int(max)).unwrap().as_int(), Some(-2 * max));
+    }
+
+    #[test]
+    fn inexact_results_are_floats() {
+        let half = ArithmeticOp::Div.apply(&int(7), &int(2)).unwrap();
+        assert_eq!(half.as_int(), None);
+        assert_eq!(half.as_number(), Some(3.5));
+        let sum = ArithmeticOp::Add.apply(&MyValue::from_number(0.5), &int(1)).unwrap();
+        assert_eq!(sum.as_number(), Some(1.5));
+        // a float that is integral is an integer again
+        let sum = ArithmeticOp::Add.apply(&MyValue::from_number(0.5), &MyValue::from_number(1.5)).unwrap();
+        assert_eq!(sum.as_int(), Some(2));
+    }
+
+    #[test]
+    fn overflow_falls_back_to_floats() {
+        let u_max = u64::MAX as i128;
+        let square = ArithmeticOp::Mul.apply(&int(u_max), &int(u_max)).unwrap();
+        assert_eq!(square.as_int(), None);
+        assert_eq!(square.as_number(), Some((u_max as f64) * (u_max as f64)));
+
+        let sum = ArithmeticOp::Add.apply(&int(i128::MAX), &int(1)).unwrap();
+        assert_eq!(sum.as_int(), None);
+        assert_eq!(sum.as_number(), Some(i128::MAX as f64 + 1.0));
+
+        let quotient = ArithmeticOp::Div.apply(&int(i128::MIN), &int(-1)).unwrap();
+        assert_eq!(quotient.as_number(), Some(-(i128::MIN as f64)));
+    }
+
+    #[test]
+    fn division_by_zero_has_no_result() {
+        assert!(ArithmeticOp::Div.apply(&int(1), &int(0)).is_none());
+        assert!(ArithmeticOp::Div.apply(&MyValue::from_number(1.5), &int(0)).is_none());
+        assert!(ArithmeticOp::Div.solve_left(&int(0), &int(1)).is_none());
+    }
+
+    #[test]
+    fn solves_for_either_operand() {
+        let big = 9007199254740993;
+        assert_eq!(ArithmeticOp::Add.solve_left(&int(1), &int(big + 1)).unwrap().as_int(), Some(big));
+        assert_eq!(ArithmeticOp::Sub.solve_right(&int(big), &int(1)).unwrap().as_int(), Some(big - 1));
+        assert_eq!(ArithmeticOp::Mul.solve_right(&int(3), &int(big * 3)).unwrap().as_int(), Some(big));
+        assert_eq!(ArithmeticOp::Div.solve_left(&int(3), &int(big)).unwrap().as_int(), Some(big * 3));
+    }
+}
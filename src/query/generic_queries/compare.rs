@@ -0,0 +1,100 @@
+use std::cmp::Ordering;
+use crate::query::generic_query::{GenericQuery, VarOrValue};
+use crate::query::stream::Frames;
+use crate::runtime::frame::Frame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    Eq,
+    Neq,
+}
+
+impl CompareOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompareOp::Gt => "gt",
+            CompareOp::Lt => "lt",
+            CompareOp::Ge => "ge",
+            CompareOp::Le => "le",
+            CompareOp::Eq => "eq",
+            CompareOp::Neq => "neq",
+        }
+    }
+
+    /// Whether two values ordered by `ordering` satisfy this comparison
+    pub fn compare(&self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Ge => ordering.is_ge(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Eq => ordering.is_eq(),
+            CompareOp::Neq => ordering.is_ne(),
+        }
+    }
+}
+
+/// `gt ?a ?b` holds if `?a > ?b`, and so on for `lt`, `ge`, `le`.
+/// Both arguments must be bound numbers, except for `eq`, which binds one side to the other when only one is bound.
+/// `eq` and `neq` compare any two values, other values than numbers are never ordered
+pub struct Compare {
+    pub op: CompareOp,
+}
+
+impl Compare {
+    pub fn new(op: CompareOp) -> Self {
+        Self {
+            op
+        }
+    }
+
+    pub fn query_frame(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Frame> {
+        match (args[0].as_value(), args[1].as_value()) {
+            (Some(a), Some(b)) => {
+                let result = match self.op {
+                    CompareOp::Eq => a == b,
+                    CompareOp::Neq => a != b,
+                    _ => self.op.compare(a.compare_numbers(b)?),
+                };
+                if result {
+                    Some(frame.clone())
+                } else {
+                    None
+                }
+            },
+            (Some(a), None) if self.op == CompareOp::Eq => {
+                let mut new_frame = frame.clone();
+                new_frame.add(args[1].get_var_name()?, a.clone());
+                Some(new_frame)
+            },
+            (None, Some(b)) if self.op == CompareOp::Eq => {
+                let mut new_frame = frame.clone();
+                new_frame.add(args[0].get_var_name()?, b.clone());
+                Some(new_frame)
+            },
+            _ => None,
+        }
+    }
+}
+
+impl GenericQuery for Compare {
+    fn query<'a>(&'a self, frame: &Frame, args: &[VarOrValue]) -> Frames<'a> {
+        if args.len() != 2 {
+            return Box::new(std::iter::empty());
+        }
+
+        let new_args = args.iter().map(|x| x.match_in_frame(frame)).collect::<Vec<_>>();
+        Box::new(self.query_frame(frame, &new_args).into_iter())
+    }
+
+    fn can_evaluate(&self, bound: &[bool]) -> bool {
+        match self.op {
+            CompareOp::Eq => bound.iter().any(|x| *x),
+            _ => bound.iter().all(|x| *x),
+        }
+    }
+}
@@ -1 +1,4 @@
 pub mod split_by;
+pub mod compare;
+pub mod arithmetic;
+pub mod regex;
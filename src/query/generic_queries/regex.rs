@@ -0,0 +1,111 @@
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use ::regex::Regex;
+use crate::query::generic_query::{GenericQuery, VarOrValue};
+use crate::query::stream::Frames;
+use crate::runtime::frame::Frame;
+use crate::runtime::value::Value as MyValue;
+
+/// The most patterns kept compiled, the cache is emptied when it grows beyond this
+const CACHE_CAPACITY: usize = 256;
+
+/// `regex pattern text ?group1 ?group2 ...` holds if `pattern` matches somewhere in `text`.
+/// The optional arguments after `text` are unified with the capture groups of the match, groups that do not
+/// participate in the match are null. When there are capture arguments, every match in `text` yields a result
+pub struct RegexMatch {
+    /// compiled patterns, so a pattern is compiled only once for all frames
+    pub cache: RwLock<HashMap<String, Arc<Regex>>>,
+}
+
+impl Default for RegexMatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegexMatch {
+    pub fn new() -> Self {
+        Self {
+            cache: RwLock::new(HashMap::new())
+        }
+    }
+
+    /// The compiled `pattern`. The cache is only locked for writing when the pattern is new,
+    /// and never while matching. Patterns built from data can be many, so the cache is bounded
+    fn regex(&self, pattern: &str) -> Option<Arc<Regex>> {
+        if let Some(x) = self.cache.read().get(pattern) {
+            return Some(x.clone());
+        }
+        let regex = Arc::new(Regex::new(pattern).ok()?);
+        let mut cache = self.cache.write();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(String::from(pattern), regex.clone());
+        Some(regex)
+    }
+
+    pub fn query_frame(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Vec<Frame>> {
+        let pattern = args[0].as_value()?.as_text()?;
+        let text = args[1].as_value()?.as_text()?;
+        let group_args = &args[2..];
+
+        if group_args.is_empty() {
+            return if self.regex(&pattern)?.is_match(&text) {
+                Some(vec![frame.clone()])
+            } else {
+                None
+            };
+        }
+
+        let matches: Vec<Vec<MyValue>> = self.regex(&pattern)?.captures_iter(&text).map(|captures| {
+            (1..=group_args.len()).map(|i| match captures.get(i) {
+                Some(m) => MyValue::from_string(m.as_str()),
+                None => MyValue::null(),
+            }).collect()
+        }).collect();
+
+        let mut result = Vec::new();
+        'matches: for groups in matches.iter() {
+            let mut new_frame = frame.clone();
+            for (arg, group) in group_args.iter().zip(groups.iter()) {
+                match arg {
+                    VarOrValue::Value(v) => {
+                        if *v != group {
+                            continue 'matches;
+                        }
+                    },
+                    VarOrValue::Var(name) => {
+                        // the same variable may appear for several groups
+                        if let Some(v) = new_frame.get(name) {
+                            if v != group {
+                                continue 'matches;
+                            }
+                        }
+                        new_frame.add(name, group.clone());
+                    },
+                }
+            }
+            result.push(new_frame);
+        }
+
+        Some(result)
+    }
+}
+
+impl GenericQuery for RegexMatch {
+    fn query<'a>(&'a self, frame: &Frame, args: &[VarOrValue]) -> Frames<'a> {
+        if args.len() < 2 {
+            return Box::new(std::iter::empty());
+        }
+
+        let new_args = args.iter().map(|x| x.match_in_frame(frame)).collect::<Vec<_>>();
+        Box::new(self.query_frame(frame, &new_args).unwrap_or_default().into_iter())
+    }
+
+    fn can_evaluate(&self, bound: &[bool]) -> bool {
+        // the pattern and the text must be known
+        bound.len() >= 2 && bound[0] && bound[1]
+    }
+}
@@ -1,23 +1,46 @@
+use std::sync::Arc;
 use crate::query::generic_query::{GenericQuery, VarOrValue};
+use crate::query::stream::Frames;
 use crate::runtime::frame::Frame;
+use crate::runtime::name::Name;
 use crate::runtime::value::Value as MyValue;
 
 pub struct SplitBy;
 
+/// The two parts of `text` split at each character boundary, from the empty prefix to the empty suffix
+fn split_in_two(text: Arc<str>) -> impl Iterator<Item = Vec<MyValue>> {
+    let boundaries = text.clone();
+    (0..=text.len())
+        .filter(move |i| boundaries.is_char_boundary(*i))
+        .map(move |i| vec![MyValue::from_string(&text[..i]), MyValue::from_string(&text[i..])])
+}
+
+/// A frame for each of `splits`, binding `names` to the parts of the split
+fn bind_splits(frame: &Frame, names: Vec<Name>, splits: impl Iterator<Item = Vec<MyValue>> + 'static) -> Frames<'static> {
+    let frame = frame.clone();
+    Box::new(splits.map(move |parts| {
+        let mut new_frame = frame.clone();
+        for (name, part) in names.iter().zip(parts) {
+            new_frame.add(name, part);
+        }
+        new_frame
+    }))
+}
+
 impl SplitBy {
-    pub fn query_var0(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Vec<Frame>> {
-        let result = String::from(args[0].as_value()?.as_loose_string()?)
-            + &args[1].as_value()?.as_loose_string()?
-            + &args[2].as_value()?.as_loose_string()?
-            == args[3].as_value()?.as_loose_string()?;
+    pub fn query_var0(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Frame> {
+        let result = args[0].as_value()?.as_text()?
+            + &args[1].as_value()?.as_text()?
+            + &args[2].as_value()?.as_text()?
+            == args[3].as_value()?.as_text()?;
         if result {
-            Some(vec![frame.clone()])
+            Some(frame.clone())
         } else {
             None
         }
     }
 
-    pub fn query_var1(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Vec<Frame>> {
+    pub fn query_var1(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Frame> {
         let mut var_index = 0;
         for (index, item) in args.iter().enumerate() {
             if item.is_var() {
@@ -30,9 +53,9 @@ impl SplitBy {
         let mut new_frame = frame.clone();
 
         if var_index == 0 {
-            let trailing = String::from(args[1].as_value()?.as_loose_string()?)
-                + &args[2].as_value()?.as_loose_string()?;
-            let all = &args[3].as_value()?.as_loose_string()?;
+            let trailing = args[1].as_value()?.as_text()?
+                + &args[2].as_value()?.as_text()?;
+            let all = &args[3].as_value()?.as_text()?;
             if !all.ends_with(&trailing) {
                 return None;
             }
@@ -40,9 +63,9 @@ impl SplitBy {
             let remain = &all[..all.len() - trailing.len()];
             new_frame.add(var_name, MyValue::from_string(remain))
         } else if var_index == 1 {
-            let leading = &args[0].as_value()?.as_loose_string()?;
-            let trailing = &args[2].as_value()?.as_loose_string()?;
-            let all = &args[3].as_value()?.as_loose_string()?;
+            let leading = &args[0].as_value()?.as_text()?;
+            let trailing = &args[2].as_value()?.as_text()?;
+            let all = &args[3].as_value()?.as_text()?;
 
             if !all.starts_with(leading) {
                 return None;
@@ -53,25 +76,26 @@ impl SplitBy {
             let remain = &all[leading.len()..all.len() - trailing.len()];
             new_frame.add(var_name, MyValue::from_string(remain));
         } else if var_index == 2 {
-            let leading = String::from(args[0].as_value()?.as_loose_string()?.as_str())
-                + &args[1].as_value()?.as_loose_string()?;
-            let all = &args[3].as_value()?.as_loose_string()?;
+            let leading = String::from(args[0].as_value()?.as_text()?.as_str())
+                + &args[1].as_value()?.as_text()?;
+            let all = &args[3].as_value()?.as_text()?;
             if !all.starts_with(&leading) {
                 return None;
             }
             let remain = &all[leading.len()..];
             new_frame.add(var_name, MyValue::from_string(remain));
         } else if var_index == 3 {
-            let all = String::from(args[0].as_value()?.as_loose_string()?.as_str())
-                + &args[1].as_value()?.as_loose_string()?
-                + &args[2].as_value()?.as_loose_string()?;
+            let all = String::from(args[0].as_value()?.as_text()?.as_str())
+                + &args[1].as_value()?.as_text()?
+                + &args[2].as_value()?.as_text()?;
             new_frame.add(var_name, MyValue::from_string(&all));
         }
 
-        Some(vec![new_frame])
+        Some(new_frame)
     }
 
-    pub fn query_var2(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Vec<Frame>> {
+    /// Every split of the text between the two unbound parts, produced as they are read
+    pub fn query_var2(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Frames<'static>> {
         let mut var_index1 = 0;
         let mut var_index2 = 0;
         for (index, item) in args.iter().enumerate() {
@@ -87,122 +111,154 @@ impl SplitBy {
             }
         }
 
-        let var_name1 = args[var_index1].get_var_name()?;
-        let var_name2 = args[var_index2].get_var_name()?;
-        let mut result = Vec::new();
+        let names = vec![args[var_index1].get_var_name()?.clone(), args[var_index2].get_var_name()?.clone()];
 
         let get_str = |index: usize| -> Option<String> {
-            Some(args[index].as_value()?.as_loose_string()?)
+            args[index].as_value()?.as_text()
         };
 
-        if var_index2 != 3 {
-            let all = &args[3].as_value()?.as_loose_string()?;
-            if var_index1 == 0 && var_index2 == 1 {
-                let end = &args[2].as_value()?.as_loose_string()?;
-                if !all.ends_with(end) {
-                    return None;
-                }
-                let remain = &all[..all.len() - end.len()];
-
-                for split_index in 0..=remain.len() {
-                    let left = &remain[0..split_index];
-                    let right = &remain[split_index..];
-                    let mut new_frame = frame.clone();
-                    new_frame.add(var_name1, MyValue::from_string(left));
-                    new_frame.add(var_name2, MyValue::from_string(right));
-                    result.push(new_frame);
-                }
-            } else if var_index1 == 0 && var_index2 == 2 {
-                let middle = &get_str(1)?;
-                for (i, _) in all.match_indices(middle) {
-                    let left = &all[0..i];
-                    let right = &all[i + middle.len()..];
-                    let mut new_frame = frame.clone();
-                    new_frame.add(var_name1, MyValue::from_string(left));
-                    new_frame.add(var_name2, MyValue::from_string(right));
-                    result.push(new_frame);
-                }
-            } else if var_index1 == 1 && var_index2 == 2 {
-                let start = &get_str(0)?;
-                if !all.starts_with(start) {
-                    println!("{}, {}", all, start);
-                    return None;
-                }
-                let remain = &all[start.len()..];
-                for split_index in 0..=remain.len() {
-                    let left = &remain[0..split_index];
-                    let right = &remain[split_index..];
-                    let mut new_frame = frame.clone();
-                    new_frame.add(var_name1, MyValue::from_string(left));
-                    new_frame.add(var_name2, MyValue::from_string(right));
-                    result.push(new_frame);
-                }
+        if var_index2 == 3 {
+            // infinitely many texts
+            return None;
+        }
+
+        let all = args[3].as_value()?.as_text()?;
+        if var_index1 == 0 && var_index2 == 1 {
+            let end = &get_str(2)?;
+            if !all.ends_with(end) {
+                return None;
+            }
+            let remain = Arc::from(&all[..all.len() - end.len()]);
+            Some(bind_splits(frame, names, split_in_two(remain)))
+        } else if var_index1 == 0 && var_index2 == 2 {
+            let middle = get_str(1)?;
+            let indexes: Vec<usize> = all.match_indices(&middle).map(|(i, _)| i).collect();
+            let splits = indexes.into_iter().map(move |i| {
+                vec![MyValue::from_string(&all[0..i]), MyValue::from_string(&all[i + middle.len()..])]
+            });
+            Some(bind_splits(frame, names, splits))
+        } else if var_index1 == 1 && var_index2 == 2 {
+            let start = &get_str(0)?;
+            if !all.starts_with(start) {
+                return None;
             }
+            let remain = Arc::from(&all[start.len()..]);
+            Some(bind_splits(frame, names, split_in_two(remain)))
         } else {
-            panic!("in SplitBy, the 4th argument cannot be a var, because there will be infinite possibilities");
+            None
         }
-
-        Some(result)
     }
 
-    pub fn query_var3(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Vec<Frame>> {
+    /// Every split of the text in three parts, produced as they are read
+    pub fn query_var3(&self, frame: &Frame, args: &[VarOrValue]) -> Option<Frames<'static>> {
         if args[3].is_var() {
-            panic!("in SplitBy, the 4th argument cannot be a var, because there will be infinite possibilities");
+            // infinitely many texts
+            return None;
         }
 
-        let vname1 = args[0].get_var_name()?;
-        let vname2 = args[1].get_var_name()?;
-        let vname3 = args[2].get_var_name()?;
-
-        let mut result = Vec::new();
-        let all = &args[3].as_value()?.as_loose_string()?;
-        for i1 in 0..=all.len() {
-            for i2 in i1..=all.len() {
-                let left = &all[0..i1];
-                let mid = &all[i1..i2];
-                let right = &all[i2..];
-                let mut new_frame = frame.clone();
-                new_frame.add(vname1, MyValue::from_string(left));
-                new_frame.add(vname2, MyValue::from_string(mid));
-                new_frame.add(vname3, MyValue::from_string(right));
-                result.push(new_frame);
-            }
-        }
+        let names = vec![
+            args[0].get_var_name()?.clone(),
+            args[1].get_var_name()?.clone(),
+            args[2].get_var_name()?.clone(),
+        ];
 
-        Some(result)
+        let all: Arc<str> = Arc::from(args[3].as_value()?.as_text()?);
+        let first = all.clone();
+        let splits = (0..=all.len())
+            .filter(move |i| first.is_char_boundary(*i))
+            .flat_map(move |i1| {
+                let all = all.clone();
+                split_in_two(Arc::from(&all[i1..])).map(move |mut parts| {
+                    parts.insert(0, MyValue::from_string(&all[..i1]));
+                    parts
+                })
+            });
+        Some(bind_splits(frame, names, splits))
     }
 }
 
 impl GenericQuery for SplitBy {
-    fn query(&self, input: &[Frame], args: &[VarOrValue]) -> Option<Vec<Frame>> {
+    fn query<'a>(&'a self, frame: &Frame, args: &[VarOrValue]) -> Frames<'a> {
         if args.len() != 4 {
-            return None;
+            return Box::new(std::iter::empty());
         }
 
-        let mut result = Vec::new();
-        for frame in input.iter() {
-            let mut var_count = 0;
-            let new_args = args.iter().map(|x| x.match_in_frame(frame)).collect::<Vec<_>>();
-            for item in new_args.iter() {
-                if item.is_var() {
-                    var_count += 1;
-                }
-            }
+        let new_args = args.iter().map(|x| x.match_in_frame(frame)).collect::<Vec<_>>();
+        let var_count = new_args.iter().filter(|x| x.is_var()).count();
 
-            if var_count == 0 {
-                result.append(&mut self.query_var0(frame, &new_args).unwrap_or(vec![]));
-            } else if var_count == 1 {
-                result.append(&mut self.query_var1(frame, &new_args).unwrap_or(vec![]));
-            } else if var_count == 2 {
-                result.append(&mut self.query_var2(frame, &new_args).unwrap_or(vec![]));
-            } else if var_count == 3 {
-                result.append(&mut self.query_var3(frame, &new_args).unwrap_or(vec![]));
-            } else {
-                panic!("in SplitBy, the 4th argument cannot be a var, because there will be infinite possibilities");
-            }
-        }
-        // println!("{:?}", result);
+        let result = if var_count == 0 {
+            self.query_var0(frame, &new_args).map(|x| -> Frames<'a> { Box::new(std::iter::once(x)) })
+        } else if var_count == 1 {
+            self.query_var1(frame, &new_args).map(|x| -> Frames<'a> { Box::new(std::iter::once(x)) })
+        } else if var_count == 2 {
+            self.query_var2(frame, &new_args)
+        } else if var_count == 3 {
+            self.query_var3(frame, &new_args)
+        } else {
+            // infinitely many texts
+            None
+        };
+
+        result.unwrap_or_else(|| Box::new(std::iter::empty()))
+    }
+
+    fn can_evaluate(&self, bound: &[bool]) -> bool {
+        // the whole string must be known, or else all of its parts, which are concatenated
+        bound.len() == 4 && (bound[3] || bound[..3].iter().all(|x| *x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The parts bound by `split_by` for each answer, `None` arguments being variables
+    fn splits(args: &[Option<&str>]) -> Vec<Vec<String>> {
+        let values: Vec<MyValue> = args.iter().map(|x| MyValue::from_string(x.unwrap_or_default())).collect();
+        let names: Vec<Name> = (0..args.len()).map(|i| Name::new(&format!("?v{}", i))).collect();
+        let args: Vec<VarOrValue> = args.iter().enumerate()
+            .map(|(i, x)| match x {
+                Some(_) => VarOrValue::Value(&values[i]),
+                None => VarOrValue::Var(names[i].clone()),
+            })
+            .collect();
+        SplitBy.query(&Frame::new(), &args)
+            .map(|frame| {
+                names.iter().filter_map(|x| frame.get(x)).map(|x| x.as_text().unwrap()).collect()
+            })
+            .collect()
+    }
+
+    fn parts(expected: &[&[&str]]) -> Vec<Vec<String>> {
+        expected.iter().map(|x| x.iter().map(|y| String::from(*y)).collect()).collect()
+    }
+
+    #[test]
+    fn splits_at_character_boundaries() {
+        assert_eq!(splits(&[None, None, Some("剑"), Some("黑剑")]), parts(&[&["", "黑"], &["黑", ""]]));
+        assert_eq!(splits(&[Some("祭"), None, None, Some("祭礼剑")]), parts(&[&["", "礼剑"], &["礼", "剑"], &["礼剑", ""]]));
+        assert_eq!(splits(&[None, Some("礼"), None, Some("祭礼剑")]), parts(&[&["祭", "剑"]]));
+        assert_eq!(splits(&[None, None, None, Some("黑剑")]), parts(&[
+            &["", "", "黑剑"], &["", "黑", "剑"], &["", "黑剑", ""],
+            &["黑", "", "剑"], &["黑", "剑", ""],
+            &["黑剑", "", ""],
+        ]));
+    }
+
+    #[test]
+    fn one_unbound_part() {
+        assert_eq!(splits(&[None, Some("_"), Some("剑"), Some("黑_剑")]), parts(&[&["黑"]]));
+        assert_eq!(splits(&[Some("黑"), None, Some("剑"), Some("黑_剑")]), parts(&[&["_"]]));
+        assert_eq!(splits(&[Some("黑"), Some("_"), None, Some("黑_剑")]), parts(&[&["剑"]]));
+        assert_eq!(splits(&[Some("黑"), Some("_"), Some("剑"), None]), parts(&[&["黑_剑"]]));
+        assert_eq!(splits(&[Some("黑"), Some("_"), Some("剑"), Some("黑_剑")]), parts(&[&[]]));
+        assert!(splits(&[Some("白"), None, Some("剑"), Some("黑_剑")]).is_empty());
+    }
 
-        Some(result)
+    #[test]
+    fn unbound_whole_text_has_no_answer() {
+        assert!(splits(&[None, Some("_"), None, None]).is_empty());
+        assert!(splits(&[None, None, None, None]).is_empty());
+        assert!(splits(&[Some("a"), None, None, None]).is_empty());
     }
 }
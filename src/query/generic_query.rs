@@ -1,20 +1,22 @@
 use std::collections::HashMap;
+use crate::query::generic_queries::arithmetic::{Arithmetic, ArithmeticOp};
+use crate::query::generic_queries::compare::{Compare, CompareOp};
+use crate::query::generic_queries::regex::RegexMatch;
 use crate::query::generic_queries::split_by::SplitBy;
+use crate::query::stream::Frames;
 use crate::runtime::frame::Frame;
+use crate::runtime::name::Name;
 use crate::runtime::value::Value as MyValue;
 
 #[derive(Clone, Debug)]
 pub enum VarOrValue<'a> {
-    Var(String),
+    Var(Name),
     Value(&'a MyValue),
 }
 
 impl<'a> VarOrValue<'a> {
     pub fn is_var(&self) -> bool {
-        match self {
-            VarOrValue::Value(_) => false,
-            _ => true
-        }
+        matches!(self, VarOrValue::Var(_))
     }
 
     pub fn as_value(&self) -> Option<&'a MyValue> {
@@ -24,17 +26,17 @@ impl<'a> VarOrValue<'a> {
         }
     }
 
-    pub fn get_var_name(&self) -> Option<&str> {
+    pub fn get_var_name(&self) -> Option<&Name> {
         match self {
             VarOrValue::Value(_) => None,
-            VarOrValue::Var(x) => Some(x.as_str())
+            VarOrValue::Var(x) => Some(x)
         }
     }
 
     pub fn match_in_frame<'b>(&'a self, frame: &'b Frame) -> VarOrValue<'b> where 'a: 'b {
         match self {
             VarOrValue::Var(x) => {
-                match frame.get(x.as_str()) {
+                match frame.get(x) {
                     Some(y) => VarOrValue::Value(y),
                     None => self.clone()
                 }
@@ -44,8 +46,15 @@ impl<'a> VarOrValue<'a> {
     }
 }
 
-pub trait GenericQuery {
-    fn query(&self, input: &[Frame], args: &[VarOrValue]) -> Option<Vec<Frame>>;
+pub trait GenericQuery: Send + Sync {
+    /// The answers for `frame`, computed as they are read. Variables of `args` bound in `frame` are read from it
+    fn query<'a>(&'a self, frame: &Frame, args: &[VarOrValue]) -> Frames<'a>;
+
+    /// Whether the query can produce its results when only the arguments marked in `bound` have values,
+    /// this is checked before a query runs
+    fn can_evaluate(&self, _bound: &[bool]) -> bool {
+        true
+    }
 }
 
 pub struct GenericQueries {
@@ -59,8 +68,8 @@ impl GenericQueries {
         }
     }
 
-    pub fn get(&self, name: &str) -> Option<&Box<dyn GenericQuery>> {
-        self.entries.get(name)
+    pub fn get(&self, name: &str) -> Option<&dyn GenericQuery> {
+        self.entries.get(name).map(|x| x.as_ref())
     }
 }
 
@@ -68,6 +77,13 @@ impl Default for GenericQueries {
     fn default() -> Self {
         let mut entries: HashMap<String, Box<dyn GenericQuery>> = HashMap::new();
         entries.insert(String::from("split_by"), Box::new(SplitBy));
+        entries.insert(String::from("regex"), Box::new(RegexMatch::new()));
+        for op in [CompareOp::Gt, CompareOp::Lt, CompareOp::Ge, CompareOp::Le, CompareOp::Eq, CompareOp::Neq] {
+            entries.insert(String::from(op.name()), Box::new(Compare::new(op)));
+        }
+        for op in [ArithmeticOp::Add, ArithmeticOp::Sub, ArithmeticOp::Mul, ArithmeticOp::Div] {
+            entries.insert(String::from(op.name()), Box::new(Arithmetic::new(op)));
+        }
 
         Self {
             entries
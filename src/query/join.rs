@@ -0,0 +1,132 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use crate::query::query::bind_variable;
+use crate::runtime::frame::{ConstraintTarget, Frame};
+
+/// Hash of the values of `keys` in `frame`, `None` if one of them is unbound
+fn key_hash(frame: &Frame, keys: &[String]) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    for key in keys.iter() {
+        hasher.write_u64(frame.get(key)?.hash);
+    }
+    Some(hasher.finish())
+}
+
+/// Add the variables of `right` to `left`, `None` if they bind a variable to different values
+fn merge(left: &Frame, right: &Frame) -> Option<Frame> {
+    let mut result = left.clone();
+    for (name, target) in right.constraints.iter() {
+        match target {
+            ConstraintTarget::NonVariable(v) => bind_variable(&mut result, name, v)?,
+            ConstraintTarget::Variable(_) => {
+                if !result.contains(name) {
+                    result.constraints.insert(name.clone(), target.clone());
+                }
+            },
+        }
+    }
+    Some(result)
+}
+
+/// Join the answers of two independent expressions on their shared variables.
+///
+/// The variables bound in every frame of both sides are hashed, and the frames of `right` are grouped by them,
+/// so each frame of `left` is only merged with the frames having the same values. Other shared variables
+/// are checked while merging. The result follows the order of `left`
+pub fn hash_join(left: &[Frame], right: &[Frame], variables: &[String]) -> Vec<Frame> {
+    let keys: Vec<String> = variables.iter()
+        .filter(|x| left.iter().all(|f| f.get(x).is_some()) && right.iter().all(|f| f.get(x).is_some()))
+        .cloned()
+        .collect();
+
+    let mut buckets: HashMap<u64, Vec<&Frame>> = HashMap::new();
+    for frame in right.iter() {
+        if let Some(hash) = key_hash(frame, &keys) {
+            buckets.entry(hash).or_default().push(frame);
+        }
+    }
+
+    let mut result = Vec::new();
+    for frame in left.iter() {
+        let matches = match key_hash(frame, &keys).and_then(|x| buckets.get(&x)) {
+            Some(x) => x,
+            None => continue,
+        };
+        result.extend(matches.iter().filter_map(|x| merge(frame, x)));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::value::Value;
+
+    fn frame(bindings: &[(&str, Value)]) -> Frame {
+        let mut frame = Frame::new();
+        for (name, value) in bindings.iter() {
+            frame.add(*name, value.clone());
+        }
+        frame
+    }
+
+    /// Every pair of frames merged, as evaluating `right` for each frame of `left` gives
+    fn nested_join(left: &[Frame], right: &[Frame]) -> Vec<Frame> {
+        left.iter().flat_map(|l| right.iter().filter_map(move |r| merge(l, r))).collect()
+    }
+
+    fn assert_same_join(left: &[Frame], right: &[Frame], variables: &[&str]) {
+        let variables: Vec<String> = variables.iter().map(|x| String::from(*x)).collect();
+        let joined: Vec<_> = hash_join(left, right, &variables).iter().map(|x| x.to_serde_map()).collect();
+        let nested: Vec<_> = nested_join(left, right).iter().map(|x| x.to_serde_map()).collect();
+        assert_eq!(joined, nested);
+    }
+
+    #[test]
+    fn joins_on_shared_variables() {
+        let int = Value::from_int;
+        let text = Value::from_string;
+        let left = vec![
+            frame(&[("?a", int(1)), ("?x", text("p"))]),
+            frame(&[("?a", int(2)), ("?x", text("q"))]),
+            frame(&[("?a", int(1)), ("?x", text("r"))]),
+            frame(&[("?a", int(3)), ("?x", text("s"))]),
+        ];
+        let right = vec![
+            frame(&[("?a", int(1)), ("?y", int(10))]),
+            frame(&[("?a", text("2")), ("?y", int(20))]),
+            frame(&[("?a", int(1)), ("?y", int(11))]),
+            frame(&[("?a", text("02")), ("?y", int(30))]),
+        ];
+        assert_same_join(&left, &right, &["?a", "?y"]);
+        assert_eq!(hash_join(&left, &right, &[String::from("?a"), String::from("?y")]).len(), 5);
+    }
+
+    #[test]
+    fn checks_variables_not_bound_everywhere() {
+        let int = Value::from_int;
+        let left = vec![
+            frame(&[("?a", int(1)), ("?b", int(1))]),
+            frame(&[("?a", int(1)), ("?b", int(2))]),
+            frame(&[("?a", int(2))]),
+        ];
+        // ?b is only bound in some frames of the right side, so it is checked while merging
+        let right = vec![
+            frame(&[("?a", int(1)), ("?b", int(2))]),
+            frame(&[("?a", int(1))]),
+            frame(&[("?a", int(2)), ("?b", int(5))]),
+        ];
+        assert_same_join(&left, &right, &["?a", "?b"]);
+    }
+
+    #[test]
+    fn cross_product_without_shared_variables() {
+        let int = Value::from_int;
+        let left = vec![frame(&[("?a", int(1))]), frame(&[("?a", int(2))])];
+        let right = vec![frame(&[("?b", int(3))]), frame(&[("?b", int(4))]), frame(&[("?b", int(5))])];
+        assert_same_join(&left, &right, &["?b"]);
+        assert_eq!(hash_join(&left, &right, &[String::from("?b")]).len(), 6);
+        assert!(hash_join(&left, &[], &[String::from("?b")]).is_empty());
+    }
+}
@@ -1,3 +1,16 @@
 pub mod query;
 pub mod generic_query;
 pub mod generic_queries;
+pub mod rule;
+pub mod error;
+pub mod analysis;
+pub mod aggregate;
+pub mod result;
+pub mod planner;
+pub mod explain;
+pub mod table;
+pub mod join;
+pub mod stream;
+pub mod run;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,455 @@
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::sync::Arc;
+use crate::ast::node::ast_expression::{ASTAggregateExpression, ASTAndExpression, ASTExpression, ASTNotExpression, ASTOptionalExpression, ASTOrExpression, ASTPrimaryExpression, ExpressionType, WrapExpression};
+use crate::file_system::file_system::FileSystem;
+use crate::query::analysis::BindingChecker;
+use crate::query::explain::{step_label, PlanStep};
+use crate::query::generic_query::GenericQueries;
+use crate::query::query::LOCALES;
+use crate::query::rule::QueryRules;
+use crate::query::table::{known_field, TableCache};
+
+/// share of rows kept by each constant or bound field of a file query
+const FIELD_SELECTIVITY: f64 = 0.1;
+/// share of frames kept by a filter, like a built-in function with all arguments bound or a negation
+const FILTER_SELECTIVITY: f64 = 0.5;
+/// answers of a rule call with no argument bound, rules are not analyzed any further
+const RULE_ROWS: f64 = 1000.0;
+/// bytes of a file per row, to estimate the rows of a file that is not loaded yet from its length
+const BYTES_PER_ROW: f64 = 512.0;
+/// bytes of a text map per entry, see `BYTES_PER_ROW`
+const BYTES_PER_TEXT: f64 = 64.0;
+/// rows of a file, or entries of a text map, that is not loaded and whose length is unknown
+const UNKNOWN_ROWS: f64 = 10000.0;
+
+/// Expected work of evaluating an expression for one input frame
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    /// rows scanned or lookups done
+    pub cost: f64,
+    /// frames produced
+    pub rows: f64,
+}
+
+impl Estimate {
+    fn new(cost: f64, rows: f64) -> Self {
+        Self {
+            cost,
+            rows
+        }
+    }
+}
+
+/// Reorders the conjuncts of `&&` chains, so that cheap and selective ones run first.
+///
+/// A conjunct is only moved where it can run, as the `BindingChecker` decides,
+/// and never across an `optional` or an aggregate, whose results depend on the variables bound before them.
+/// Among the conjuncts that can run next, the one with the lowest estimated cost and result size is chosen
+pub struct Planner<'a> {
+    rules: &'a QueryRules,
+    generic_query: &'a GenericQueries,
+    file_system: &'a dyn FileSystem,
+    /// parsed files, shared with the evaluation, for their sizes. Files are never loaded for planning
+    tables: &'a TableCache,
+}
+
+/// The query or rule body being planned
+struct PlanScope<'b> {
+    root: &'b ASTExpression,
+    params: &'b [String],
+    rule: Option<&'b String>,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(rules: &'a QueryRules, generic_query: &'a GenericQueries, file_system: &'a dyn FileSystem, tables: &'a TableCache) -> Self {
+        Self {
+            rules,
+            generic_query,
+            file_system,
+            tables,
+        }
+    }
+
+    pub fn plan_query(&self, ast: &WrapExpression) -> WrapExpression {
+        let root = ast.read();
+        let scope = PlanScope {
+            root: &root,
+            params: &[],
+            rule: None,
+        };
+        self.plan(ast, &scope, &HashSet::new())
+    }
+
+    /// Plan the body of a rule. The callers may bind any of the parameters, so none is assumed bound
+    pub fn plan_rule(&self, name: &String, params: &[String], body: &WrapExpression) -> WrapExpression {
+        let root = body.read();
+        let scope = PlanScope {
+            root: &root,
+            params,
+            rule: Some(name),
+        };
+        self.plan(body, &scope, &HashSet::new())
+    }
+
+    fn plan(&self, ast: &WrapExpression, scope: &PlanScope, bound: &HashSet<String>) -> WrapExpression {
+        let handle = ast.read();
+        let planned = match &handle.ast_type {
+            ExpressionType::AndExpression(_) => {
+                let mut conjuncts = Vec::new();
+                flatten_and(ast, &mut conjuncts);
+
+                let mut bound = bound.clone();
+                let mut ordered = Vec::new();
+                let mut segment = Vec::new();
+                for conjunct in conjuncts {
+                    if is_barrier(&conjunct.read()) {
+                        ordered.append(&mut self.order(std::mem::take(&mut segment), scope, &mut bound));
+                        ordered.push(self.plan(&conjunct, scope, &bound));
+                        bound = self.bound_after(&conjunct.read(), scope, &bound);
+                    } else {
+                        segment.push(conjunct);
+                    }
+                }
+                ordered.append(&mut self.order(segment, scope, &mut bound));
+
+                let mut result: Option<WrapExpression> = None;
+                for x in ordered {
+                    result = Some(match result {
+                        Some(left) => wrap(ASTExpression::from_and_expression(ASTAndExpression::new(left, x))),
+                        None => x,
+                    });
+                }
+                return result.unwrap_or_else(|| ast.clone());
+            },
+            ExpressionType::OrExpression(o) => {
+                ASTExpression::from_or_expression(ASTOrExpression::new(
+                    self.plan(&o.left, scope, bound),
+                    self.plan(&o.right, scope, bound),
+                ))
+            },
+            ExpressionType::NotExpression(n) => {
+                ASTExpression::from_not_expression(ASTNotExpression::new(self.plan(&n.expression, scope, bound)))
+            },
+            ExpressionType::OptionalExpression(o) => {
+                ASTExpression::from_optional_expression(ASTOptionalExpression::new(self.plan(&o.expression, scope, bound)))
+            },
+            ExpressionType::AggregateExpression(a) => {
+                ASTExpression::from_aggregate_expression(ASTAggregateExpression {
+                    op: a.op,
+                    result: a.result.clone(),
+                    value: a.value.clone(),
+                    expression: self.plan(&a.expression, scope, bound),
+                    group_by: a.group_by.clone(),
+                })
+            },
+            _ => return ast.clone(),
+        };
+        wrap(planned)
+    }
+
+    /// Greedily order `conjuncts`, each planned with the variables bound before it. `bound` is updated to the
+    /// variables bound after all of them. Conjuncts that cannot run anywhere keep their order, at the end
+    fn order(&self, mut conjuncts: Vec<WrapExpression>, scope: &PlanScope, bound: &mut HashSet<String>) -> Vec<WrapExpression> {
+        let mut result = Vec::new();
+        while !conjuncts.is_empty() {
+            let mut best: Option<(usize, f64, HashSet<String>)> = None;
+            for (index, conjunct) in conjuncts.iter().enumerate() {
+                let handle = conjunct.read();
+                let after = match self.check(&handle, scope, bound) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let estimate = self.estimate(&handle, bound);
+                let score = estimate.cost + estimate.rows;
+                if best.as_ref().is_none_or(|(_, x, _)| score < *x) {
+                    best = Some((index, score, after));
+                }
+            }
+
+            let (index, after) = match best {
+                Some((index, _, after)) => (index, after),
+                None => {
+                    let after = self.bound_after(&conjuncts[0].read(), scope, bound);
+                    (0, after)
+                },
+            };
+            let conjunct = conjuncts.remove(index);
+            result.push(self.plan(&conjunct, scope, bound));
+            *bound = after;
+        }
+        result
+    }
+
+    /// Variables bound after `ast`, if it can run with `bound` variables bound before
+    fn check(&self, ast: &ASTExpression, scope: &PlanScope, bound: &HashSet<String>) -> Option<HashSet<String>> {
+        BindingChecker::new(self.rules, self.generic_query)
+            .check_part(ast, scope.root, scope.params, scope.rule, bound)
+            .ok()
+    }
+
+    fn bound_after(&self, ast: &ASTExpression, scope: &PlanScope, bound: &HashSet<String>) -> HashSet<String> {
+        self.check(ast, scope, bound).unwrap_or_else(|| {
+            let mut result = bound.clone();
+            result.extend(ast.variables());
+            result
+        })
+    }
+
+    /// Whether `ast`, run for `frames` frames binding `bound`, is cheaper to evaluate once on its own and hash join
+    /// with the frames, than to evaluate for each frame. Only an expression that can run with nothing bound,
+    /// and whose answers do not depend on what is bound before it, can be joined. An expression that the bound
+    /// variables already make cheaper, like a lookup in an index, is never joined, only independent scans are
+    pub fn prefer_hash_join(&self, ast: &ASTExpression, bound: &HashSet<String>, frames: f64) -> bool {
+        if frames < 2.0 || is_barrier(ast) {
+            return false;
+        }
+        let empty = HashSet::new();
+        let independent = BindingChecker::new(self.rules, self.generic_query)
+            .check_part(ast, ast, &[], None, &empty)
+            .is_ok();
+        if !independent {
+            return false;
+        }
+
+        let alone = self.estimate(ast, &empty);
+        let nested = self.estimate(ast, bound);
+        if nested.cost < alone.cost {
+            return false;
+        }
+        alone.cost + alone.rows + frames < frames * nested.cost
+    }
+
+    /// Estimate the work of evaluating `ast` for one frame with `bound` variables bound,
+    /// following the access paths of `QueryProgram`
+    pub fn estimate(&self, ast: &ASTExpression, bound: &HashSet<String>) -> Estimate {
+        match &ast.ast_type {
+            ExpressionType::PrimaryExpression(p) => self.access_path(p, bound).1,
+            ExpressionType::AndExpression(a) => {
+                let left = self.estimate(&a.left.read(), bound);
+                let mut inner_bound = bound.clone();
+                inner_bound.extend(a.left.read().variables());
+                let right = self.estimate(&a.right.read(), &inner_bound);
+                Estimate::new(left.cost + left.rows * right.cost, left.rows * right.rows)
+            },
+            ExpressionType::OrExpression(o) => {
+                let left = self.estimate(&o.left.read(), bound);
+                let right = self.estimate(&o.right.read(), bound);
+                Estimate::new(left.cost + right.cost, left.rows + right.rows)
+            },
+            ExpressionType::NotExpression(n) => {
+                let inner = self.estimate(&n.expression.read(), bound);
+                Estimate::new(inner.cost, FILTER_SELECTIVITY)
+            },
+            ExpressionType::OptionalExpression(o) => {
+                let inner = self.estimate(&o.expression.read(), bound);
+                Estimate::new(inner.cost, inner.rows.max(1.0))
+            },
+            ExpressionType::AggregateExpression(a) => {
+                let inner = self.estimate(&a.expression.read(), bound);
+                Estimate::new(inner.cost + inner.rows, 1.0)
+            },
+            _ => Estimate::new(0.0, 1.0),
+        }
+    }
+
+    /// How `QueryProgram` evaluates a predicate with `bound` variables bound, and the estimated work for one frame
+    pub fn access_path(&self, ast: &ASTPrimaryExpression, bound: &HashSet<String>) -> (String, Estimate) {
+        let name = ast.predicate.as_str();
+        let known: Vec<bool> = ast.args.iter().map(|x| is_known(&x.read(), bound)).collect();
+
+        if self.rules.contains(name) {
+            let rows = RULE_ROWS * FIELD_SELECTIVITY.powi(known.iter().filter(|x| **x).count() as i32);
+            let kind = if self.rules.is_recursive(name) { "tabled rule" } else { "rule" };
+            (format!("{} {}", kind, name), Estimate::new(rows, rows))
+        } else if LOCALES.contains(&name) {
+            let size = self.text_map_size(name);
+            match known.as_slice() {
+                [true, _] => (format!("lookup key in text map {}", name), Estimate::new(1.0, 1.0)),
+                [_, true] => (format!("lookup text in text map {}", name), Estimate::new(1.0, 1.0)),
+                _ => (format!("scan text map {} ({} entries)", name, size), Estimate::new(size, size)),
+            }
+        } else if self.generic_query.get(name).is_some() {
+            let rows = if known.iter().all(|x| *x) { FILTER_SELECTIVITY } else { 1.0 };
+            (format!("built-in {}", name), Estimate::new(1.0, rows))
+        } else {
+            let size = self.table_size(name);
+            let mut rows = size;
+            for (index, arg) in ast.args.iter().enumerate() {
+                let arg = arg.read();
+                if index == 0 && known[0] && !matches!(arg.ast_type, ExpressionType::ObjectPattern(_)) {
+                    // the row itself is known
+                    rows = rows.min(1.0);
+                } else {
+                    rows *= FIELD_SELECTIVITY.powi(known_fields(&arg, bound) as i32);
+                }
+            }
+            // a known row is read by itself, otherwise a known value is looked up in an index,
+            // and only the matching rows are read
+            let row = ast.args.first().map(|x| x.read());
+            let indexed = match (known.as_slice(), row) {
+                ([true, ..], Some(_)) => Some(Vec::new()),
+                ([_, true], _) => Some(ast.path.clone()),
+                (_, Some(row)) => known_field(&row, |x| is_known(x, bound).then_some(())).map(|(path, _)| path),
+                _ => None,
+            };
+            match indexed {
+                Some(path) if path.is_empty() => (format!("lookup row in {}", name), Estimate::new(1.0, rows)),
+                Some(path) => {
+                    let path: String = path.iter().map(|x| x.to_string()).collect();
+                    (format!("lookup index on {}{} ({} rows)", name, path, size), Estimate::new(rows, rows))
+                },
+                None => (format!("scan file {} ({} rows)", name, size), Estimate::new(size, rows)),
+            }
+        }
+    }
+
+    /// The plan of a query planned by `plan_query`, with the expected number of frames after each step
+    pub fn explain(&self, ast: &WrapExpression) -> PlanStep {
+        let root = ast.read();
+        let scope = PlanScope {
+            root: &root,
+            params: &[],
+            rule: None,
+        };
+        self.explain_step(&root, &scope, &HashSet::new(), 1.0).0
+    }
+
+    /// The plan of `ast` evaluated for `frames` frames, and the variables bound after it
+    fn explain_step(&self, ast: &ASTExpression, scope: &PlanScope, bound: &HashSet<String>, frames: f64) -> (PlanStep, HashSet<String>) {
+        let mut step = PlanStep {
+            label: step_label(ast),
+            access: None,
+            rows: frames,
+            children: Vec::new(),
+        };
+        match &ast.ast_type {
+            ExpressionType::PrimaryExpression(p) => {
+                let (access, estimate) = self.access_path(p, bound);
+                step.access = Some(access);
+                step.rows = frames * estimate.rows;
+            },
+            ExpressionType::AndExpression(_) => {
+                let mut conjuncts = Vec::new();
+                flatten_and_ref(ast, &mut conjuncts);
+                let mut bound = bound.clone();
+                for (index, conjunct) in conjuncts.iter().enumerate() {
+                    let conjunct = conjunct.read();
+                    let (mut child, after) = self.explain_step(&conjunct, scope, &bound, step.rows);
+                    if index > 0 && self.prefer_hash_join(&conjunct, &bound, step.rows) {
+                        // evaluated once with nothing bound, as `QueryProgram::query_and` does
+                        let rows = child.rows;
+                        child = self.explain_step(&conjunct, scope, &HashSet::new(), 1.0).0;
+                        child.access = Some(match child.access {
+                            Some(x) => format!("hash join, {}", x),
+                            None => String::from("hash join"),
+                        });
+                        child.rows = rows;
+                    }
+                    step.rows = child.rows;
+                    step.children.push(child);
+                    bound = after;
+                }
+                return (step, bound);
+            },
+            ExpressionType::OrExpression(o) => {
+                let (left, _) = self.explain_step(&o.left.read(), scope, bound, frames);
+                let (right, _) = self.explain_step(&o.right.read(), scope, bound, frames);
+                step.rows = left.rows + right.rows;
+                step.children = vec![left, right];
+            },
+            ExpressionType::NotExpression(n) => {
+                let (inner, _) = self.explain_step(&n.expression.read(), scope, bound, frames);
+                step.rows = frames * FILTER_SELECTIVITY;
+                step.children.push(inner);
+            },
+            ExpressionType::OptionalExpression(o) => {
+                let (inner, _) = self.explain_step(&o.expression.read(), scope, bound, frames);
+                step.rows = inner.rows.max(frames);
+                step.children.push(inner);
+            },
+            ExpressionType::AggregateExpression(a) => {
+                let (inner, _) = self.explain_step(&a.expression.read(), scope, bound, frames);
+                if !a.group_by.is_empty() {
+                    step.rows = inner.rows.max(frames);
+                }
+                step.children.push(inner);
+            },
+            _ => {},
+        }
+        let after = self.bound_after(ast, scope, bound);
+        (step, after)
+    }
+
+    /// Row count of the file named `name`, estimated from the length of the file until it is loaded
+    fn table_size(&self, name: &str) -> f64 {
+        let path = format!("ExcelBinOutput/{}.json", name);
+        match self.tables.loaded(&path) {
+            Some(x) => x.rows.len() as f64,
+            None => self.estimate_size(&path, BYTES_PER_ROW),
+        }
+    }
+
+    /// Entry count of the text map of `locale`, see `table_size`
+    fn text_map_size(&self, locale: &str) -> f64 {
+        let path = format!("TextMap/TextMap{}.json", locale);
+        match self.tables.loaded_text_map(&path) {
+            Some(x) => x.entries.len() as f64,
+            None => self.estimate_size(&path, BYTES_PER_TEXT),
+        }
+    }
+
+    fn estimate_size(&self, path: &str, bytes_per_row: f64) -> f64 {
+        self.file_system.size(path).map_or(UNKNOWN_ROWS, |x| (x as f64 / bytes_per_row).ceil())
+    }
+}
+
+fn wrap(ast: ASTExpression) -> WrapExpression {
+    Arc::new(RwLock::new(ast))
+}
+
+/// Collect the operands of a chain of `&&`, the chain itself being borrowed
+fn flatten_and_ref(ast: &ASTExpression, result: &mut Vec<WrapExpression>) {
+    if let ExpressionType::AndExpression(a) = &ast.ast_type {
+        flatten_and(&a.left, result);
+        flatten_and(&a.right, result);
+    }
+}
+
+/// Collect the operands of a chain of `&&`
+fn flatten_and(ast: &WrapExpression, result: &mut Vec<WrapExpression>) {
+    match &ast.read().ast_type {
+        ExpressionType::AndExpression(a) => {
+            flatten_and(&a.left, result);
+            flatten_and(&a.right, result);
+        },
+        _ => result.push(ast.clone()),
+    }
+}
+
+/// Whether the result of `ast` depends on which variables are bound before it, other than by filtering
+fn is_barrier(ast: &ASTExpression) -> bool {
+    match &ast.ast_type {
+        ExpressionType::OptionalExpression(_) | ExpressionType::AggregateExpression(_) => true,
+        ExpressionType::AndExpression(a) => is_barrier(&a.left.read()) || is_barrier(&a.right.read()),
+        ExpressionType::OrExpression(o) => is_barrier(&o.left.read()) || is_barrier(&o.right.read()),
+        ExpressionType::NotExpression(n) => is_barrier(&n.expression.read()),
+        _ => false,
+    }
+}
+
+/// Whether the value of an argument is known before evaluation
+fn is_known(ast: &ASTExpression, bound: &HashSet<String>) -> bool {
+    match ast.get_var_name() {
+        Some(name) => bound.contains(name.as_str()),
+        None => !matches!(ast.ast_type, ExpressionType::ObjectPattern(_)),
+    }
+}
+
+/// Number of known values in an argument, counting each field of an object pattern
+fn known_fields(ast: &ASTExpression, bound: &HashSet<String>) -> usize {
+    match &ast.ast_type {
+        ExpressionType::ObjectPattern(o) => o.fields.iter().map(|(_, x)| known_fields(&x.read(), bound)).sum(),
+        _ => is_known(ast, bound) as usize,
+    }
+}
@@ -1,55 +1,116 @@
-use std::path::{Path, PathBuf};
-use crate::ast::node::ast_expression::{ASTAndExpression, ASTExpression, ASTOrExpression, ASTPrimaryExpression, ExpressionType};
-use crate::ast::parser::MyParser;
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use crate::ast::node::ast_expression::{ASTAggregateExpression, ASTAndExpression, ASTExpression, ASTNotExpression, ASTOptionalExpression, ASTOrExpression, ASTPrimaryExpression, ExpressionType, WrapExpression};
+use crate::ast::node::ast_program::{ASTModifiers, ASTProgram};
+use rayon::prelude::*;
+use crate::ast::parser::{HIDDEN_VARIABLE_PREFIX, MyParser};
 use crate::file_system::cached_file_system::CachedFileSystem;
 use crate::file_system::file_system::FileSystem;
 use crate::file_system::naive_file_system::NaiveFileSystem;
+use crate::query::aggregate::Accumulator;
+use crate::query::analysis::BindingChecker;
+use crate::query::error::QueryError;
+use crate::query::explain::{PlanStep, Profile};
 use crate::query::generic_query::{GenericQueries, VarOrValue};
+use crate::query::join::hash_join;
+use crate::query::planner::Planner;
+use crate::query::result::{row_key, QueryResult, QueryRows};
+use crate::query::stream::{Chunks, Frames, Profiled, PARALLEL_FRAMES, PARALLEL_ROWS};
+use crate::query::table::{known_field, Table, TableCache};
+use crate::query::rule::{QueryRule, QueryRules, RuleAnswer, RuleTable, RuleTables};
+use crate::query::run::QueryRun;
+use crate::runtime::accessor::access_all;
 use crate::runtime::frame::Frame;
-use crate::runtime::value::{NonVariableValue, Value};
-
-pub fn is_match_n(f1: &Frame, ast: &ASTPrimaryExpression, v: &[&Value]) -> Option<Frame> {
-    let mut new_frame = f1.clone();
-    let n = ast.args.len();
-
-    for i in 0..n {
-        let handle = ast.args[i].borrow();
-        if handle.is_var() {
-            let var_name = handle.get_var_name().unwrap();
-            if let Some(x) = f1.get(&var_name) {
-                if x.hash != v[i].hash {
-                    return None;
-                }
+use crate::runtime::name::Name;
+use crate::runtime::value::{Value, ValueType};
+
+/// Predicates that query a text map
+pub const LOCALES: [&str; 13] = ["CHS", "CHT", "DE", "EN", "ES", "FR", "ID", "JP", "KR", "PT", "RU", "TH", "VI"];
+
+/// Unify `ast` with `value`, binding the unbound variables of `ast` in `frame`.
+/// An object pattern matches an object having all of its keys, unifying each of them with its sub pattern
+pub fn unify(frame: &mut Frame, ast: &ASTExpression, value: &Value) -> Option<()> {
+    match &ast.ast_type {
+        ExpressionType::Variable(x) if !x.is_path => bind_variable(frame, &x.name, value)?,
+        ExpressionType::ObjectPattern(pattern) => {
+            let object = match value.value_type.as_ref() {
+                ValueType::Object(x) => x,
+                _ => return None,
+            };
+            for (key, sub_pattern) in pattern.fields.iter() {
+                unify(frame, &sub_pattern.read(), object.get(key)?)?;
             }
-            new_frame.add(&var_name, v[i].clone());
-        } else {
-            let v2 = handle.try_to_value()?;
-            if v2.hash != v[i].hash {
+        },
+        _ => {
+            let v2 = ast.value_in_frame(frame)?;
+            if v2 != *value {
                 return None;
             }
         }
     }
 
-    Some(new_frame)
+    Some(())
 }
 
-pub fn access_serde(v: &serde_json::Value, accessor: &[&str]) -> Option<Value> {
-    let mut temp = v;
-    for a in accessor.iter() {
-        if !temp.is_object() {
+/// Bind the variable `name` to `value`, or check that it is already bound to an equal value
+pub fn bind_variable(frame: &mut Frame, name: &Name, value: &Value) -> Option<()> {
+    if let Some(bound) = frame.get(name) {
+        if bound != value {
             return None;
         }
-        let t = temp.as_object().unwrap().get(*a)?;
-        temp = t;
     }
+    frame.add(name, value.clone());
+    Some(())
+}
 
-    let ret = NonVariableValue::from_serde(temp.clone()).to_value();
-    Some(ret)
+pub fn is_match_n(f1: &Frame, ast: &ASTPrimaryExpression, v: &[&Value]) -> Option<Frame> {
+    let mut new_frame = f1.clone();
+
+    for (arg, value) in ast.args.iter().zip(v.iter()) {
+        unify(&mut new_frame, &arg.read(), value)?;
+    }
+
+    Some(new_frame)
+}
+
+/// Unify the arguments of a rule call with the parameters bound in one answer of the rule body.
+/// Parameters left unbound by the body leave the corresponding argument unbound
+fn bind_rule_answer(frame: &Frame, ast: &ASTPrimaryExpression, answer: &[Option<Value>]) -> Option<Frame> {
+    let mut new_frame = frame.clone();
+
+    for (value, arg) in answer.iter().zip(ast.args.iter()) {
+        if let Some(value) = value {
+            unify(&mut new_frame, &arg.read(), value)?;
+        }
+    }
+
+    Some(new_frame)
+}
+
+fn primary(ast: &ASTExpression) -> Option<&ASTPrimaryExpression> {
+    match &ast.ast_type {
+        ExpressionType::PrimaryExpression(p) => Some(p),
+        _ => None,
+    }
 }
 
 pub struct QueryProgram {
     pub generic_query: GenericQueries,
+    pub rules: RwLock<QueryRules>,
     pub file_system: Box<dyn FileSystem>,
+    /// parsed Excel tables and their indexes
+    pub tables: TableCache,
+}
+
+/// A parsed, planned and checked query
+struct PreparedQuery {
+    expression: WrapExpression,
+    /// variables of the query as written, without hidden ones
+    variables: Vec<String>,
+    select: Option<Vec<String>>,
+    modifiers: ASTModifiers,
 }
 
 impl Default for QueryProgram {
@@ -57,233 +118,695 @@ impl Default for QueryProgram {
         let fs1 = NaiveFileSystem::new(Path::new("E:\\rust\\gdp\\sub").to_path_buf());
         let fs2 = CachedFileSystem::new(Box::new(fs1));
 
+        QueryProgram::new(Box::new(fs2))
+    }
+}
+
+impl QueryProgram {
+    pub fn new(file_system: Box<dyn FileSystem>) -> Self {
         QueryProgram {
             generic_query: GenericQueries::default(),
-            file_system: Box::new(fs2),
+            rules: RwLock::new(QueryRules::new()),
+            file_system,
+            tables: TableCache::new(),
         }
     }
-}
 
-impl QueryProgram {
-    // pub fn is_match(&self, )
+    pub fn add_rule(&self, rule: QueryRule) -> Result<(), QueryError> {
+        self.add_rules(vec![rule])
+    }
 
-    pub fn query(&self, q: &str) -> Option<Vec<Frame>> {
+    /// Add rules to this program, all of them or none when one of them can not be added
+    pub fn add_rules(&self, rules: Vec<QueryRule>) -> Result<(), QueryError> {
+        let mut current = self.rules.write();
+        current.add_all(rules)?;
+        self.plan_rules(&mut current);
+        Ok(())
+    }
+
+    /// Plan the bodies of all rules again, as a new rule changes the rules calling it.
+    /// Called rules are planned first, as planning a body checks the rules it calls
+    fn plan_rules(&self, rules: &mut QueryRules) {
+        for name in rules.dependency_order() {
+            let plans: Vec<WrapExpression> = {
+                let planner = Planner::new(rules, &self.generic_query, self.file_system.as_ref(), &self.tables);
+                rules.get(&name).unwrap_or_default().iter().map(|x| planner.plan_rule(&x.name, &x.params, &x.body)).collect()
+            };
+            if let Some(clauses) = rules.entries.get_mut(&name) {
+                for (clause, plan) in clauses.iter_mut().zip(plans) {
+                    clause.plan = plan;
+                }
+            }
+        }
+    }
+
+    fn parse(q: &str) -> Result<(ASTProgram, Vec<QueryRule>), QueryError> {
         let parser = MyParser;
-        let ast = parser.parsestring_expression(q)?;
-        // println!("{:?}", ast);
+        let mut program = parser.parsestring_program(q).ok_or(QueryError::Syntax)?;
+        // println!("{:?}", program);
+        let rules = program.rules.drain(..).map(QueryRule::from_ast).collect();
+        Ok((program, rules))
+    }
 
-        let empty_frame = vec![Frame::new()];
-        let x = self.query_internal(&empty_frame, &ast.borrow());
-        x
+    /// Parse, plan and check a program. Rule definitions are kept in this `QueryProgram` and can be used by later queries,
+    /// `None` for a program consisting of only rule definitions
+    fn prepare(&self, q: &str) -> Result<Option<PreparedQuery>, QueryError> {
+        let (program, rules) = Self::parse(q)?;
+        self.add_rules(rules)?;
+        let rules = self.rules.read();
+        self.plan_program(&rules, program)
     }
 
-    fn query_internal(&self, input: &[Frame], ast: &ASTExpression) -> Option<Vec<Frame>> {
-        match &ast.ast_type {
-            ExpressionType::PrimaryExpression(p) => {
-                let predicate = p.predicate[0].as_str();
-                let locales = vec!["CHS", "CHT", "DE", "EN", "ES", "FR", "ID", "JP", "KR", "PT", "RU", "TH", "VI"];
+    /// Plan and check the query of a program, which calls `rules`
+    fn plan_program(&self, rules: &QueryRules, program: ASTProgram) -> Result<Option<PreparedQuery>, QueryError> {
+        let expression = match program.expression {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        // columns follow the query as written, not as planned
+        let variables: Vec<String> = expression.read().variables().into_iter()
+            .filter(|x| !x.starts_with(HIDDEN_VARIABLE_PREFIX))
+            .collect();
+
+        let expression = Planner::new(rules, &self.generic_query, self.file_system.as_ref(), &self.tables).plan_query(&expression);
+        BindingChecker::new(rules, &self.generic_query).check(&expression.read())?;
+
+        let modifiers = program.modifiers;
+        let used = program.select.iter().flatten().chain(modifiers.order_by.iter().map(|x| &x.variable));
+        for variable in used {
+            if !variables.contains(variable) {
+                return Err(QueryError::UnknownVariable { variable: variable.clone() });
+            }
+        }
 
-                if locales.iter().find(|x| **x == predicate).is_some() {
-                    self.query_locale(input, p)
-                } else {
-                    self.query_simple(input, p)
-                }
-            },
-            ExpressionType::AndExpression(a) => self.query_and(input, a),
-            ExpressionType::OrExpression(a) => self.query_or(input, a),
-            _ => None
+        Ok(Some(PreparedQuery {
+            expression,
+            variables,
+            select: program.select,
+            modifiers,
+        }))
+    }
+
+    /// Run a prepared query, keeping the answers of recursive rules and the counters in `run`
+    fn run(&self, prepared: PreparedQuery, run: Arc<QueryRun>) -> QueryRows<'_> {
+        let modifiers = prepared.modifiers;
+        let mut columns = prepared.variables;
+
+        let start = Box::new(std::iter::once(Frame::new()));
+        let mut frames: Frames = Box::new(self.stream(&run, start, prepared.expression, 0).map(|mut frame| {
+            frame.remove_prefixed(HIDDEN_VARIABLE_PREFIX);
+            frame
+        }));
+        if !modifiers.order_by.is_empty() {
+            // sorting needs every answer
+            let mut result = QueryResult::new(columns.clone(), frames.collect());
+            result.sort(&modifiers.order_by);
+            frames = Box::new(result.frames.into_iter());
+        }
+        if let Some(selected) = prepared.select {
+            let kept = selected.clone();
+            frames = Box::new(frames.map(move |mut frame| {
+                frame.retain(&kept);
+                frame
+            }));
+            columns = selected;
+        }
+        if modifiers.distinct {
+            let mut seen = HashSet::new();
+            let keys = columns.clone();
+            frames = Box::new(frames.filter(move |frame| seen.insert(row_key(frame, &keys))));
         }
+        frames = Box::new(frames.skip(modifiers.offset.unwrap_or(0)));
+        if let Some(limit) = modifiers.limit {
+            // later answers are never computed
+            frames = Box::new(frames.take(limit));
+        }
+
+        QueryRows::new(columns, frames)
     }
 
-    pub fn query_and(&self, input: &[Frame], ast: &ASTAndExpression) -> Option<Vec<Frame>> {
-        let r1 = self.query_internal(input, &ast.left.borrow())?;
-        let r2 = self.query_internal(&r1, &ast.right.borrow());
-        r2
+    /// Parse a program and start running it, the answers are computed as they are read.
+    /// Rule definitions are kept in this `QueryProgram` and can be used by later queries,
+    /// a program consisting of only rule definitions yields no answer
+    pub fn query_iter(&self, q: &str) -> Result<QueryRows<'_>, QueryError> {
+        self.start(q, Arc::new(QueryRun::new()))
     }
 
-    pub fn query_or(&self, input: &[Frame], ast: &ASTOrExpression) -> Option<Vec<Frame>> {
-        let mut r1 = self.query_internal(input, &ast.left.borrow()).unwrap_or(Vec::new());
-        let mut r2 = self.query_internal(input, &ast.right.borrow()).unwrap_or(Vec::new());
-        r1.append(&mut r2);
-        if r1.len() == 0 {
-            None
-        } else {
-            Some(r1)
+    fn start(&self, q: &str, run: Arc<QueryRun>) -> Result<QueryRows<'_>, QueryError> {
+        match self.prepare(q)? {
+            Some(x) => Ok(self.run(x, run)),
+            None => Ok(QueryRows::new(Vec::new(), Box::new(std::iter::empty()))),
         }
     }
 
-    pub fn query_simple(&self, input: &[Frame], ast: &ASTPrimaryExpression) -> Option<Vec<Frame>> {
-        let mut result = Vec::new();
+    /// Parse and run a program, collecting every answer, see `query_iter`
+    pub fn try_query(&self, q: &str) -> Result<QueryResult, QueryError> {
+        Ok(self.query_iter(q)?.into_result())
+    }
 
-        if let Some(mut x) = self.query_file_data_1(input, ast) {
-            result.append(&mut x);
-        }
+    /// The plan of a program's query, without running it. `None` for a program consisting of only rule definitions.
+    /// The query may call the rules the program defines, but they are not kept in this `QueryProgram`
+    pub fn explain(&self, q: &str) -> Result<Option<PlanStep>, QueryError> {
+        let (program, definitions) = Self::parse(q)?;
+        let mut rules = self.rules.read().clone();
+        rules.add_all(definitions)?;
+        self.plan_rules(&mut rules);
+
+        let prepared = match self.plan_program(&rules, program)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let planner = Planner::new(&rules, &self.generic_query, self.file_system.as_ref(), &self.tables);
+        Ok(Some(planner.explain(&prepared.expression)))
+    }
 
-        if let Some(mut x) = self.query_file_data_other(input, ast) {
-            result.append(&mut x);
-        }
+    /// Run a program like `try_query`, counting the frames, rows and file loads of each step
+    pub fn profile(&self, q: &str) -> Result<(QueryResult, Profile), QueryError> {
+        let run = Arc::new(QueryRun::profiled());
+        let result = self.start(q, run.clone())?.into_result();
+        Ok((result, run.take_profile()))
+    }
 
-        if let Some(mut x) = self.query_global_function(input, ast) {
-            result.append(&mut x);
+    /// Parse and run a program, collecting every answer. `None` when it fails,
+    /// or when it uses a predicate that is not a rule nor a built-in function and has no file or text map
+    pub fn query(&self, q: &str) -> Option<Vec<Frame>> {
+        let prepared = match self.prepare(q).ok()? {
+            Some(x) => x,
+            None => return Some(Vec::new()),
+        };
+        if !self.predicates_exist(&prepared.expression.read()) {
+            return None;
         }
+        Some(self.run(prepared, Arc::new(QueryRun::new())).into_result().frames)
+    }
 
-        if result.len() == 0 {
-            None
-        } else {
-            Some(result)
-        }
+    fn predicates_exist(&self, ast: &ASTExpression) -> bool {
+        let rules = self.rules.read();
+        ast.predicates().iter().all(|x| {
+            if rules.contains(x) || self.generic_query.get(x).is_some() {
+                true
+            } else if LOCALES.contains(&x.as_str()) {
+                self.file_system.exists(&format!("TextMap/TextMap{}.json", x))
+            } else {
+                self.file_system.exists(&format!("ExcelBinOutput/{}.json", x))
+            }
+        })
     }
 
-    pub fn query_file_data_1(&self, input: &[Frame], ast: &ASTPrimaryExpression) -> Option<Vec<Frame>> {
-        let filename = &ast.predicate[0];
-        let path = format!("ExcelBinOutput/{}.json", filename);
-        let file_content = self.file_system.read(&path);
+    /// Evaluate `ast` for `input`, collecting every answer. Used where all answers are needed at once, like rule bodies
+    fn query_internal(&self, run: &Arc<QueryRun>, input: Vec<Frame>, ast: &WrapExpression) -> Vec<Frame> {
+        // nested in the running step, if any
+        let depth = run.record(|x| x.running_depth()).flatten().map_or(0, |x| x + 1);
+        self.stream(run, Box::new(input.into_iter()), ast.clone(), depth).collect()
+    }
 
-        if file_content.is_none() {
-            None
-        } else {
-            if ast.args.len() != 1 {
-                None
-            } else {
-                let mut result: Vec<Frame> = Vec::new();
-                let json_value: serde_json::Value = serde_json::from_str(&file_content.as_ref().unwrap()).ok()?;
-                if !json_value.is_array() {
-                    return None;
-                }
-                let arr = json_value.as_array().unwrap();
+    /// Evaluate `ast` for each frame of `input`. Answers are computed as they are read,
+    /// only operators that need every answer, like aggregates, read their input to the end first
+    fn stream<'a>(&'a self, run: &Arc<QueryRun>, input: Frames<'a>, ast: WrapExpression, depth: usize) -> Frames<'a> {
+        let step = match run.record(|x| x.step(&ast.read(), depth)) {
+            Some(x) => x,
+            None => return self.stream_step(run, input, ast, depth),
+        };
+
+        let counted = run.clone();
+        let input = Box::new(input.inspect(move |_| {
+            counted.record(|x| x.input(step));
+        }));
+        Box::new(Profiled::new(run.clone(), step, self.stream_step(run, input, ast, depth)))
+    }
 
-                for frame in input.iter() {
-                    for item in arr {
-                        let wrapped_value: Value = NonVariableValue::from_serde(item.clone()).to_value();
-                        if let Some(x) = is_match_n(frame, ast, &vec![&wrapped_value]) {
-                            result.push(x);
-                        }
-                    }
+    fn stream_step<'a>(&'a self, run: &Arc<QueryRun>, input: Frames<'a>, ast: WrapExpression, depth: usize) -> Frames<'a> {
+        let handle = ast.read();
+        match &handle.ast_type {
+            ExpressionType::PrimaryExpression(_) => {
+                let ast = ast.clone();
+                let run = run.clone();
+                if !run.parallel() || self.calls_rules(&handle) {
+                    return Box::new(input.flat_map(move |frame| self.query_primary(&run, frame, ast.clone())));
                 }
+                // large chunks of frames are looked up in parallel, keeping the order of the input
+                Box::new(Chunks::new(input).flat_map(move |chunk| -> Frames<'a> {
+                    if chunk.len() < PARALLEL_FRAMES {
+                        let (run, ast) = (run.clone(), ast.clone());
+                        return Box::new(chunk.into_iter().flat_map(move |frame| self.query_primary(&run, frame, ast.clone())));
+                    }
+                    let results: Vec<Vec<Frame>> = chunk.into_par_iter()
+                        .map(|frame| self.query_primary(&run, frame, ast.clone()).collect())
+                        .collect();
+                    Box::new(results.into_iter().flatten())
+                }))
+            },
+            ExpressionType::AndExpression(a) => self.query_and(run, input, a, depth),
+            ExpressionType::OrExpression(a) => self.query_or(run, input, a, depth),
+            ExpressionType::NotExpression(a) => self.query_not(run, input, a, depth),
+            ExpressionType::OptionalExpression(a) => self.query_optional(run, input, a, depth),
+            ExpressionType::AggregateExpression(_) => {
+                let (run, ast) = (run.clone(), ast.clone());
+                Box::new(input.flat_map(move |frame| {
+                    match &ast.read().ast_type {
+                        ExpressionType::AggregateExpression(a) => self.query_aggregate(&run, frame, a, depth),
+                        _ => Vec::new(),
+                    }
+                }))
+            },
+            _ => Box::new(std::iter::empty()),
+        }
+    }
 
-                Some(result)
+    /// The right side is evaluated for chunks of answers of the left side, or once and hash joined with them
+    /// when it is independent and would otherwise scan for each answer
+    pub fn query_and<'a>(&'a self, run: &Arc<QueryRun>, input: Frames<'a>, ast: &ASTAndExpression, depth: usize) -> Frames<'a> {
+        let left = self.stream(run, input, ast.left.clone(), depth + 1);
+        let run = run.clone();
+        let right = ast.right.clone();
+        let variables = right.read().variables();
+
+        // the answers of the right side, once it is hash joined
+        let mut joined: Option<Vec<Frame>> = None;
+        Box::new(Chunks::new(left).flat_map(move |chunk| -> Frames<'a> {
+            if joined.is_none() && self.prefer_hash_join(&right.read(), &chunk) {
+                let start = Box::new(std::iter::once(Frame::new()));
+                joined = Some(self.stream(&run, start, right.clone(), depth + 1).collect());
+            }
+            match joined.as_ref() {
+                Some(x) => Box::new(hash_join(&chunk, x, &variables).into_iter()),
+                None => self.stream(&run, Box::new(chunk.into_iter()), right.clone(), depth + 1),
             }
+        }))
+    }
+
+    fn prefer_hash_join(&self, right: &ASTExpression, frames: &[Frame]) -> bool {
+        let bound: HashSet<String> = match frames.first() {
+            Some(x) => x.constraints.keys().map(|k| k.to_string()).collect(),
+            None => return false,
+        };
+        let rules = self.rules.read();
+        Planner::new(&rules, &self.generic_query, self.file_system.as_ref(), &self.tables)
+            .prefer_hash_join(right, &bound, frames.len() as f64)
+    }
+
+    /// The answers of both sides, for each input frame.
+    /// Both sides are evaluated concurrently, unless they call rules
+    pub fn query_or<'a>(&'a self, run: &Arc<QueryRun>, input: Frames<'a>, ast: &ASTOrExpression, depth: usize) -> Frames<'a> {
+        let (left, right) = (ast.left.clone(), ast.right.clone());
+        let parallel = run.parallel();
+        let run = run.clone();
+        let branch = move |frame: Frame, ast: &WrapExpression| self.stream(&run, Box::new(std::iter::once(frame)), ast.clone(), depth + 1);
+        if !parallel || self.calls_rules(&left.read()) || self.calls_rules(&right.read()) {
+            return Box::new(input.flat_map(move |frame| branch(frame.clone(), &left).chain(branch(frame, &right))));
         }
+
+        Box::new(Chunks::new(input).flat_map(move |chunk| {
+            let results: Vec<Vec<Frame>> = chunk.into_par_iter().map(|frame| {
+                let (mut l, r) = rayon::join(
+                    || branch(frame.clone(), &left).collect::<Vec<Frame>>(),
+                    || branch(frame.clone(), &right).collect::<Vec<Frame>>(),
+                );
+                l.extend(r);
+                l
+            }).collect();
+            results.into_iter().flatten()
+        }))
     }
 
-    pub fn query_file_data_other(&self, input: &[Frame], ast: &ASTPrimaryExpression) -> Option<Vec<Frame>> {
-        let filename = &ast.predicate[0];
-        let path = format!("ExcelBinOutput/{}.json", filename);
-        let file_content = self.file_system.read(&path);
+    /// Negation as failure, keeps the frames for which the negated expression has no result
+    pub fn query_not<'a>(&'a self, run: &Arc<QueryRun>, input: Frames<'a>, ast: &ASTNotExpression, depth: usize) -> Frames<'a> {
+        let expression = ast.expression.clone();
+        let run = run.clone();
+        Box::new(input.filter(move |frame| {
+            let start = Box::new(std::iter::once(frame.clone()));
+            self.stream(&run, start, expression.clone(), depth + 1).next().is_none()
+        }))
+    }
 
-        if file_content.is_none() {
-            None
-        } else {
-            if ast.args.len() != 2 {
-                return None;
+    pub fn query_optional<'a>(&'a self, run: &Arc<QueryRun>, input: Frames<'a>, ast: &ASTOptionalExpression, depth: usize) -> Frames<'a> {
+        let expression = ast.expression.clone();
+        let variables = expression.read().variables();
+        let run = run.clone();
+        Box::new(input.flat_map(move |frame| -> Frames<'a> {
+            let start = Box::new(std::iter::once(frame.clone()));
+            let mut answers = self.stream(&run, start, expression.clone(), depth + 1).peekable();
+            if answers.peek().is_some() {
+                return Box::new(answers);
             }
 
-            let mut result: Vec<Frame> = Vec::new();
-            let json_value: serde_json::Value = serde_json::from_str(&file_content.as_ref().unwrap()).ok()?;
-            if !json_value.is_array() {
-                return None;
-            }
-            let arr = json_value.as_array().unwrap();
-
-            for frame in input.iter() {
-                for item in arr {
-                    let accessor: Vec<_> = ast.predicate.iter().skip(1).map(|x| x.as_str()).collect();
-                    let final_value = access_serde(item, &accessor)?;
-                    let wrapped_item = NonVariableValue::from_serde(item.clone()).to_value();
-                    if let Some(x) = is_match_n(frame, ast, &vec![&wrapped_item, &final_value]) {
-                        result.push(x);
-                    }
+            // no answer, the variables of the expression are bound to null instead
+            let mut new_frame = frame;
+            for variable in variables.iter() {
+                if new_frame.get(variable).is_none() {
+                    new_frame.add(variable, Value::null());
                 }
             }
+            Box::new(std::iter::once(new_frame))
+        }))
+    }
+
+    /// Aggregate the answers of the inner expression for an input frame, one result per group.
+    /// Without `by`, there is always one group, so that counting no answer gives 0
+    pub fn query_aggregate(&self, run: &Arc<QueryRun>, frame: Frame, ast: &ASTAggregateExpression, depth: usize) -> Vec<Frame> {
+        // groups in the order they first appear, indexed by the hashes of their values
+        let mut groups: Vec<(Vec<Value>, Accumulator)> = Vec::new();
+        let mut group_index: HashMap<Vec<Value>, usize> = HashMap::new();
+        if ast.group_by.is_empty() {
+            groups.push((Vec::new(), Accumulator::new(ast.op)));
+            group_index.insert(Vec::new(), 0);
+        }
+
+        let start = Box::new(std::iter::once(frame.clone()));
+        for answer in self.stream(run, start, ast.expression.clone(), depth + 1) {
+            let values: Vec<Value> = ast.group_by.iter()
+                .map(|x| answer.get(x).cloned().unwrap_or_else(Value::null))
+                .collect();
+            let index = *group_index.entry(values.clone()).or_insert_with(|| {
+                groups.push((values, Accumulator::new(ast.op)));
+                groups.len() - 1
+            });
+            groups[index].1.add(ast.value.as_ref().and_then(|x| answer.get(x)));
+        }
+
+        let mut result = Vec::new();
+        for (values, accumulator) in groups.iter() {
+            let value = match accumulator.result() {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut new_frame = frame.clone();
+            let bound = ast.group_by.iter().zip(values.iter())
+                .try_for_each(|(name, x)| bind_variable(&mut new_frame, name, x))
+                .and_then(|_| bind_variable(&mut new_frame, &ast.result, &value));
+            if bound.is_some() {
+                result.push(new_frame);
+            }
+        }
+
+        result
+    }
 
-            Some(result)
+    /// The answers of a predicate for one frame
+    fn query_primary(&self, run: &Arc<QueryRun>, frame: Frame, ast: WrapExpression) -> Frames<'_> {
+        let handle = ast.read();
+        let p = match &handle.ast_type {
+            ExpressionType::PrimaryExpression(p) => p,
+            _ => return Box::new(std::iter::empty()),
+        };
+
+        let predicate = p.predicate.as_str();
+        if self.rules.read().contains(predicate) {
+            let answers = self.query_rule(run, std::slice::from_ref(&frame), p).unwrap_or_default();
+            Box::new(answers.into_iter())
+        } else if LOCALES.contains(&predicate) {
+            self.query_locale(run, frame, ast.clone())
+        } else {
+            let functions = self.query_global_function(&frame, p);
+            Box::new(self.query_file_data_1(run, frame.clone(), ast.clone())
+                .chain(self.query_file_data_other(run, frame, ast.clone()))
+                .chain(functions))
         }
     }
 
-    pub fn query_locale(&self, input: &[Frame], ast: &ASTPrimaryExpression) -> Option<Vec<Frame>> {
-        let locale = ast.predicate[0].as_str();
-        let content = self.file_system.read_serde(&format!("TextMap/TextMap{}.json", locale))?;
+    /// The table of the file named `name`
+    fn table(&self, run: &QueryRun, name: &str) -> Option<Arc<Table>> {
+        let path = format!("ExcelBinOutput/{}.json", name);
+        run.file(&run.tables, &path, || self.tables.get(self.file_system.as_ref(), &path))
+    }
 
-        if !content.is_object() {
-            return None;
+    /// Rows of `table` that the row argument `arg` can match in `frame`: looked up when the row is bound
+    /// or a field of its pattern is known, `None` for every row otherwise
+    fn candidate_rows(&self, table: &Table, arg: &ASTExpression, frame: &Frame) -> Option<Vec<(usize, Value)>> {
+        match arg.value_in_frame(frame) {
+            Some(row) => Some(table.lookup(&[], &row)),
+            None => known_field(arg, |x| x.value_in_frame(frame)).map(|(path, value)| table.lookup(&path, &value)),
         }
-        let obj = content.as_object().unwrap();
+    }
 
-        if ast.args.len() != 2 {
-            return None;
+    pub fn query_file_data_1(&self, run: &Arc<QueryRun>, frame: Frame, ast: WrapExpression) -> Frames<'_> {
+        let (table, rows) = {
+            let handle = ast.read();
+            let table = match primary(&handle).filter(|p| p.args.len() == 1).and_then(|p| self.table(run, &p.predicate)) {
+                Some(x) => x,
+                None => return Box::new(std::iter::empty()),
+            };
+            let rows = self.candidate_rows(&table, &primary(&handle).unwrap().args[0].read(), &frame);
+            (table, rows)
+        };
+
+        let count = rows.as_ref().map_or(table.rows.len(), |x| x.len());
+        self.scan(run, count, move |i| {
+            let row = &table.rows[rows.as_ref().map_or(i, |x| x[i].0)];
+            let handle = ast.read();
+            primary(&handle).and_then(|p| is_match_n(&frame, p, &[row])).into_iter().collect()
+        })
+    }
+
+    pub fn query_file_data_other(&self, run: &Arc<QueryRun>, frame: Frame, ast: WrapExpression) -> Frames<'_> {
+        let handle = ast.read();
+        let p = match primary(&handle).filter(|p| p.args.len() == 2) {
+            Some(x) => x,
+            None => return Box::new(std::iter::empty()),
+        };
+        let table = match self.table(run, &p.predicate) {
+            Some(x) => x,
+            None => return Box::new(std::iter::empty()),
+        };
+
+        let row_known = p.args[0].read().value_in_frame(&frame).is_some();
+        if let Some(value) = p.args[1].read().value_in_frame(&frame).filter(|_| !row_known) {
+            // the value is known but not the row, rows having it are looked up in an index on the path
+            let entries = table.lookup(&p.path, &value);
+            let ast = ast.clone();
+            return self.scan(run, entries.len(), move |i| {
+                let (row, v) = &entries[i];
+                let handle = ast.read();
+                primary(&handle).and_then(|p| is_match_n(&frame, p, &[&table.rows[*row], v])).into_iter().collect()
+            });
         }
 
-        let mut result = Vec::new();
-        // if either side of the query param is constant, use it to speed up.
-        if !ast.args[0].borrow().is_var() {
-            // key is constant
-            let key = ast.args[0].borrow().as_loose_string()?;
+        let rows = self.candidate_rows(&table, &p.args[0].read(), &frame);
+        let count = rows.as_ref().map_or(table.rows.len(), |x| x.len());
+        let ast = ast.clone();
+        self.scan(run, count, move |i| {
+            let handle = ast.read();
+            let p = primary(&handle).unwrap();
+            let item = &table.rows[rows.as_ref().map_or(i, |x| x[i].0)];
+            // a wildcard in the path gives every element of an array its own result,
+            // and rows without the field are skipped
+            let values = access_all(item, &p.path).unwrap_or_default();
+            values.into_iter().filter_map(|value| is_match_n(&frame, p, &[item, value])).collect()
+        })
+    }
+
+    pub fn query_locale(&self, run: &Arc<QueryRun>, frame: Frame, ast: WrapExpression) -> Frames<'_> {
+        let handle = ast.read();
+        let p = match primary(&handle).filter(|p| p.args.len() == 2) {
+            Some(x) => x,
+            None => return Box::new(std::iter::empty()),
+        };
+        let path = format!("TextMap/TextMap{}.json", p.predicate);
+        let text_map = match run.file(&run.text_maps, &path, || self.tables.get_text_map(self.file_system.as_ref(), &path)) {
+            Some(x) => x,
+            None => return Box::new(std::iter::empty()),
+        };
+
+        // if either side is known in this frame, it is looked up, rather than scanning every entry
+        let key = p.args[0].read().value_in_frame(&frame);
+        let text = p.args[1].read().value_in_frame(&frame);
+        if let Some(key) = key {
+            // key is constant, bound, or a path into a bound variable
+            run.record(|x| x.scan(1));
+            let result = key.as_text().and_then(|key| {
+                let v = text_map.get(&key)?;
+                let k = Value::from_string(&key);
+                is_match_n(&frame, p, &[&k, v])
+            });
+            Box::new(result.into_iter())
+        } else if let Some(text) = text {
+            // value is known, its keys are found in the reverse index
+            let text = match text.as_text() {
+                Some(x) => x,
+                None => return Box::new(std::iter::empty()),
+            };
+            let keys = text_map.keys_of(&text);
+            let v = Value::from_string(&text);
+            let ast = ast.clone();
+            self.scan(run, keys.len(), move |i| {
+                let handle = ast.read();
+                primary(&handle).and_then(|p| is_match_n(&frame, p, &[&Value::from_string(&keys[i]), &v])).into_iter().collect()
+            })
+        } else {
+            // this is slow, avoid using two unbound vars in a locale query
+            let ast = ast.clone();
+            self.scan(run, text_map.entries.len(), move |i| {
+                let (key, value) = text_map.entries.get_index(i).unwrap();
+                let handle = ast.read();
+                primary(&handle).and_then(|p| is_match_n(&frame, p, &[&Value::from_string(key), value])).into_iter().collect()
+            })
+        }
+    }
+
+    /// Evaluate `f` for the index of each of the `count` rows or entries read by a step, as they are needed.
+    /// Large scans run in parallel, in blocks of `PARALLEL_ROWS` items
+    fn scan<'a>(&'a self, run: &Arc<QueryRun>, count: usize, f: impl Fn(usize) -> Vec<Frame> + Send + Sync + 'a) -> Frames<'a> {
+        if !run.parallel() || count < PARALLEL_ROWS {
+            let run = run.clone();
+            return Box::new((0..count).flat_map(move |i| {
+                run.record(|x| x.scan(1));
+                f(i)
+            }));
+        }
 
-            let k = Value::from_string(&key);
-            let v = NonVariableValue::from_serde(obj.get(&key).unwrap().clone()).to_value();
+        let starts = (0..count).step_by(PARALLEL_ROWS);
+        Box::new(starts.flat_map(move |start| {
+            (start..count.min(start + PARALLEL_ROWS)).into_par_iter().flat_map_iter(&f).collect::<Vec<Frame>>()
+        }))
+    }
 
-            for f in input.iter() {
-                if let Some(x) = is_match_n(f, ast, &vec![&k, &v]) {
-                    result.push(x);
+    /// Whether `ast` calls a rule. The tables of recursive rules are shared by the whole run,
+    /// so rules always run on the calling thread
+    fn calls_rules(&self, ast: &ASTExpression) -> bool {
+        let rules = self.rules.read();
+        ast.predicates().iter().any(|x| rules.contains(x))
+    }
+
+    /// The answers of a built-in function for one frame, computed as they are read
+    pub fn query_global_function(&self, frame: &Frame, ast: &ASTPrimaryExpression) -> Frames<'_> {
+        let generic_query = match self.generic_query.get(ast.predicate.as_str()) {
+            Some(x) => x,
+            None => return Box::new(std::iter::empty()),
+        };
+
+        let handles: Vec<_> = ast.args.iter().map(|x| x.read()).collect();
+        let mut values = Vec::new();
+        for arg in handles.iter() {
+            if arg.is_var() {
+                values.push(None);
+            } else {
+                match arg.value_in_frame(frame) {
+                    Some(x) => values.push(Some(x)),
+                    // a path that does not exist in this frame, or an object pattern,
+                    // which never matches the arguments of a built-in function
+                    None => return Box::new(std::iter::empty()),
                 }
             }
-        } else if !ast.args[1].borrow().is_var() {
-            // value is constant
-            let text = ast.args[1].borrow().as_loose_string()?;
-            // println!("tex: {}", text);
+        }
 
-            let v = Value::from_string(&text);
-            for key in obj.keys() {
-                let value = obj.get(key.as_str()).unwrap();
-                let value_str = value.as_str()?;
-                if value_str == "" {
-                    continue;
+        let args: Vec<_> = handles.iter().zip(values.iter()).map(|(arg, value)| match value {
+            Some(x) => VarOrValue::Value(x),
+            None => VarOrValue::Var(arg.get_var_name().unwrap()),
+        }).collect();
+        generic_query.query(frame, &args)
+    }
+
+    pub fn query_rule(&self, run: &Arc<QueryRun>, input: &[Frame], ast: &ASTPrimaryExpression) -> Option<Vec<Frame>> {
+        let name = ast.predicate.as_str();
+        let (recursive, component) = {
+            let rules = self.rules.read();
+            (rules.is_recursive(name), rules.component(name)?)
+        };
+
+        let mut result = Vec::new();
+        for frame in input.iter() {
+            let args: Vec<Option<Value>> = ast.args.iter().map(|arg| arg.read().value_in_frame(frame)).collect();
+
+            let answers = if recursive {
+                self.tabled_rule_answers(run, name, component, &args)
+            } else {
+                self.rule_answers(run, name, &args)
+            };
+            for answer in answers.iter() {
+                if let Some(x) = bind_rule_answer(frame, ast, answer) {
+                    result.push(x);
                 }
-                if value_str == text {
-                    let k = Value::from_string(key.as_str());
-                    for f in input.iter() {
-                        if let Some(x) = is_match_n(f, ast, &vec![&k, &v]) {
-                            result.push(x);
+            }
+        }
+
+        Some(result)
+    }
+
+    /// Evaluate every rule named `name` once, with the parameters bound to `args`
+    fn rule_answers(&self, run: &Arc<QueryRun>, name: &str, args: &[Option<Value>]) -> Vec<RuleAnswer> {
+        // the rules are not locked while their bodies run
+        let clauses = self.rules.read().get(name).map(|x| x.to_vec()).unwrap_or_default();
+
+        let mut result = Vec::new();
+        for clause in clauses.iter() {
+            if clause.params.len() != args.len() {
+                continue;
+            }
+
+            // the body is evaluated in its own frame, so variables inside the body never clash with the caller's
+            let mut inner_frame = Frame::new();
+            let mut consistent = true;
+            for (param, arg) in clause.params.iter().zip(args.iter()) {
+                if let Some(v) = arg {
+                    if let Some(x) = inner_frame.get(param) {
+                        if x != v {
+                            consistent = false;
+                            break;
                         }
                     }
+                    inner_frame.add(param, v.clone());
                 }
             }
-        } else {
-            // this is slow, avoid using two vars in a locale query
-            for frame in input.iter() {
-                for key in obj.keys() {
-                    let value = obj.get(key.as_str()).unwrap();
-                    if value.as_str()? == "" {
-                        continue;
-                    }
-                    let v2 = NonVariableValue::from_serde(value.clone()).to_value();
-                    if let Some(x) = is_match_n(frame, ast, &vec![&Value::from_string(key.as_str()), &v2]) {
-                        result.push(x);
-                    }
-                }
+            if !consistent {
+                continue;
+            }
+
+            let answers = self.query_internal(run, vec![inner_frame], &clause.plan);
+            for answer in answers.iter() {
+                result.push(clause.params.iter().map(|x| answer.get(x).cloned()).collect());
             }
         }
 
-        Some(result)
+        result
     }
 
-    pub fn query_global_function(&self, input: &[Frame], ast: &ASTPrimaryExpression) -> Option<Vec<Frame>> {
-        let name = ast.predicate[0].as_str();
-        let generic_query = self.generic_query.get(name)?;
+    /// Evaluate a recursive rule to a fixpoint, see `RuleTables`
+    fn tabled_rule_answers(&self, run: &Arc<QueryRun>, name: &str, component: usize, args: &[Option<Value>]) -> Vec<RuleAnswer> {
+        let key = RuleTables::call_key(name, args);
+
+        let leader = {
+            let mut tables = run.rule_tables.write();
+            if let Some(table) = tables.tables.get(&key) {
+                if table.complete {
+                    return table.answers.clone();
+                }
+            }
+
+            let leader = !tables.active.contains_key(&component);
+            if leader {
+                tables.active.insert(component, 0);
+            }
+            let iteration = tables.active[&component];
+
+            match tables.tables.get_mut(&key) {
+                // a recursive call, or a call that was already evaluated in this iteration
+                Some(table) if table.iteration == iteration => return table.answers.clone(),
+                Some(table) => table.iteration = iteration,
+                None => {
+                    tables.tables.insert(key.clone(), RuleTable::new(component, iteration));
+                }
+            }
+            leader
+        };
 
-        let handles: Vec<_> = ast.args.iter().map(|x| x.borrow()).collect();
-        let values: Vec<_> = ast.args.iter().map(|x| x.borrow().try_to_value()).collect();
+        loop {
+            for answer in self.rule_answers(run, name, args) {
+                run.rule_tables.write().add_answer(&key, answer);
+            }
+            if !leader {
+                break;
+            }
 
-        let mut args = Vec::new();
-        for (index, arg) in handles.iter().enumerate() {
-            if arg.is_var() {
-                let var_name = arg.get_var_name().unwrap();
-                args.push(VarOrValue::Var(var_name));
-            } else {
-                args.push(VarOrValue::Value(values[index].as_ref().unwrap()));
+            let mut tables = run.rule_tables.write();
+            if !tables.changed.remove(&component) {
+                tables.complete(component);
+                break;
             }
+            *tables.active.get_mut(&component).unwrap() += 1;
+            let iteration = tables.active[&component];
+            tables.tables.get_mut(&key).unwrap().iteration = iteration;
         }
 
-        generic_query.query(input, &args)
+        run.rule_tables.read().tables[&key].answers.clone()
     }
 }
@@ -0,0 +1,91 @@
+use indexmap::IndexMap;
+use std::cmp::Ordering;
+use crate::ast::node::ast_program::ASTOrderKey;
+use crate::query::stream::Frames;
+use crate::runtime::frame::Frame;
+use crate::runtime::value::Value;
+
+/// Answers of a query, with the variables to show for each of them
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    /// the variables listed by `select`, or every variable of the query in the order they first appear
+    pub columns: Vec<String>,
+    pub frames: Vec<Frame>,
+}
+
+impl QueryResult {
+    pub fn new(columns: Vec<String>, frames: Vec<Frame>) -> Self {
+        Self {
+            columns,
+            frames,
+        }
+    }
+
+    /// Sort the answers by `keys`, the first key first. Answers with a variable unbound sort as if it were null
+    pub fn sort(&mut self, keys: &[ASTOrderKey]) {
+        let null = Value::null();
+        self.frames.sort_by(|a, b| {
+            for key in keys.iter() {
+                let x = a.get(&key.variable).unwrap_or(&null);
+                let y = b.get(&key.variable).unwrap_or(&null);
+                let ordering = if key.descending { y.order(x) } else { x.order(y) };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            Ordering::Equal
+        });
+    }
+
+    /// Each answer as a JSON object with its keys in the order of `columns`, unbound variables are left out
+    pub fn to_serde_rows(&self) -> Vec<SerdeRow> {
+        self.frames.iter().map(|frame| to_serde_row(frame, &self.columns)).collect()
+    }
+}
+
+/// Answers of a query, computed as they are read, with the variables to show for each of them
+pub struct QueryRows<'a> {
+    pub columns: Vec<String>,
+    frames: Frames<'a>,
+}
+
+impl<'a> QueryRows<'a> {
+    pub fn new(columns: Vec<String>, frames: Frames<'a>) -> Self {
+        Self {
+            columns,
+            frames,
+        }
+    }
+
+    /// Read every answer
+    pub fn into_result(self) -> QueryResult {
+        QueryResult::new(self.columns, self.frames.collect())
+    }
+}
+
+impl Iterator for QueryRows<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.frames.next()
+    }
+}
+
+/// Values of `columns` in `frame`, equal for answers that show the same
+pub fn row_key(frame: &Frame, columns: &[String]) -> Vec<Option<Value>> {
+    columns.iter().map(|x| frame.get(x).cloned()).collect()
+}
+
+/// An answer as it is printed, a JSON object keeping the order of its keys
+pub type SerdeRow = IndexMap<String, serde_json::Value>;
+
+/// An answer as a JSON object with its keys in the order of `columns`, unbound variables are left out
+pub fn to_serde_row(frame: &Frame, columns: &[String]) -> SerdeRow {
+    let mut row = SerdeRow::new();
+    for column in columns.iter() {
+        if let Some(x) = frame.get(column) {
+            row.insert(column.clone(), x.to_serde());
+        }
+    }
+    row
+}
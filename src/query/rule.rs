@@ -0,0 +1,287 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::node::ast_expression::WrapExpression;
+use crate::ast::node::ast_program::ASTRuleDefinition;
+use crate::query::error::QueryError;
+use crate::runtime::value::Value;
+
+/// A named predicate, `rule (name ?p1 ?p2 ...) { body }`.
+/// Several rules can share a name, a call then yields the union of all of them
+#[derive(Debug, Clone)]
+pub struct QueryRule {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: WrapExpression,
+    /// the body as planned, which is what runs. Planned again whenever the rules change
+    pub plan: WrapExpression,
+}
+
+impl QueryRule {
+    pub fn from_ast(ast: ASTRuleDefinition) -> Self {
+        Self {
+            name: ast.name,
+            params: ast.params,
+            plan: ast.body.clone(),
+            body: ast.body,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct QueryRules {
+    pub entries: HashMap<String, Vec<QueryRule>>,
+    /// strongly connected component of every rule name in the rule dependency graph
+    pub components: HashMap<String, usize>,
+    /// rules that (directly or indirectly) call themselves
+    pub recursive: HashSet<String>,
+}
+
+impl Default for QueryRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QueryRules {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            components: HashMap::new(),
+            recursive: HashSet::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[QueryRule]> {
+        self.entries.get(name).map(|x| x.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    pub fn is_recursive(&self, name: &str) -> bool {
+        self.recursive.contains(name)
+    }
+
+    pub fn component(&self, name: &str) -> Option<usize> {
+        self.components.get(name).cloned()
+    }
+
+    /// Add a rule, unless it makes a rule negate itself through recursion
+    pub fn add(&mut self, rule: QueryRule) -> Result<(), QueryError> {
+        self.add_all(vec![rule])
+    }
+
+    /// Add rules, all of them or none when one of them makes a rule negate itself through recursion
+    pub fn add_all(&mut self, rules: Vec<QueryRule>) -> Result<(), QueryError> {
+        let previous = self.entries.clone();
+        for rule in rules {
+            self.entries.entry(rule.name.clone()).or_default().push(rule);
+        }
+        self.analyze();
+
+        if let Err(e) = self.check_stratified() {
+            self.entries = previous;
+            self.analyze();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// A rule may only negate rules that do not depend on it, so that the negated rules can be fully evaluated first
+    fn check_stratified(&self) -> Result<(), QueryError> {
+        for (name, clauses) in self.entries.iter() {
+            for clause in clauses.iter() {
+                for negated in clause.body.read().negated_predicates() {
+                    if self.contains(&negated) && self.component(&negated) == self.component(name) {
+                        return Err(QueryError::UnstratifiedNegation {
+                            rule: name.clone(),
+                            negated,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of all rules, those called by a rule before it, unless they call each other
+    pub fn dependency_order(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.entries.keys().cloned().collect();
+        // components are numbered in the order they are completed, after every component they depend on
+        names.sort_by_key(|x| (self.components.get(x).cloned(), x.clone()));
+        names
+    }
+
+    /// Rules that the rules named `name` call
+    pub fn dependencies(&self, name: &str) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        for rule in self.get(name).unwrap_or(&[]) {
+            for p in rule.body.read().predicates() {
+                if self.contains(&p) && !result.contains(&p) {
+                    result.push(p);
+                }
+            }
+        }
+        result
+    }
+
+    /// Find the strongly connected components of the rule dependency graph (Tarjan's algorithm),
+    /// rules in a component with a cycle are recursive
+    fn analyze(&mut self) {
+        struct State {
+            index: HashMap<String, usize>,
+            low: HashMap<String, usize>,
+            stack: Vec<String>,
+            on_stack: HashSet<String>,
+            components: Vec<Vec<String>>,
+        }
+
+        fn visit(rules: &QueryRules, name: &str, state: &mut State) {
+            let index = state.index.len();
+            state.index.insert(String::from(name), index);
+            state.low.insert(String::from(name), index);
+            state.stack.push(String::from(name));
+            state.on_stack.insert(String::from(name));
+
+            for dep in rules.dependencies(name) {
+                if !state.index.contains_key(&dep) {
+                    visit(rules, &dep, state);
+                    let low = state.low[name].min(state.low[&dep]);
+                    state.low.insert(String::from(name), low);
+                } else if state.on_stack.contains(&dep) {
+                    let low = state.low[name].min(state.index[&dep]);
+                    state.low.insert(String::from(name), low);
+                }
+            }
+
+            if state.low[name] == state.index[name] {
+                let mut component = Vec::new();
+                while let Some(x) = state.stack.pop() {
+                    state.on_stack.remove(&x);
+                    let done = x == name;
+                    component.push(x);
+                    if done {
+                        break;
+                    }
+                }
+                state.components.push(component);
+            }
+        }
+
+        let mut state = State {
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            on_stack: HashSet::new(),
+            components: Vec::new(),
+        };
+        let mut names: Vec<_> = self.entries.keys().cloned().collect();
+        names.sort();
+        for name in names.iter() {
+            if !state.index.contains_key(name) {
+                visit(self, name, &mut state);
+            }
+        }
+
+        self.components.clear();
+        self.recursive.clear();
+        for (id, component) in state.components.iter().enumerate() {
+            let is_recursive = component.len() > 1 || self.dependencies(&component[0]).contains(&component[0]);
+            for name in component.iter() {
+                self.components.insert(name.clone(), id);
+                if is_recursive {
+                    self.recursive.insert(name.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Values of a rule's parameters, `None` for an unbound parameter
+pub type RuleAnswer = Vec<Option<Value>>;
+
+/// A call to a rule, by its name and arguments
+pub type CallKey = (String, RuleAnswer);
+
+/// Answers of one call to a recursive rule, with a given pattern of bound arguments
+pub struct RuleTable {
+    pub answers: Vec<RuleAnswer>,
+    pub seen: HashSet<RuleAnswer>,
+    pub component: usize,
+    /// the fixpoint iteration in which this table was last evaluated
+    pub iteration: usize,
+    pub complete: bool,
+}
+
+impl RuleTable {
+    pub fn new(component: usize, iteration: usize) -> Self {
+        Self {
+            answers: Vec::new(),
+            seen: HashSet::new(),
+            component,
+            iteration,
+            complete: false,
+        }
+    }
+
+    /// Returns whether the answer is new
+    pub fn add(&mut self, answer: RuleAnswer) -> bool {
+        if self.seen.insert(answer.clone()) {
+            self.answers.push(answer);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Memo tables for recursive rules.
+///
+/// A call to a recursive rule is evaluated repeatedly, each time reusing the answers found so far for recursive
+/// calls, until an iteration produces no new answer. Since answers only ever grow, this terminates whenever the
+/// rule has finitely many answers, which is the case for rules that only join data from files and text maps
+pub struct RuleTables {
+    pub tables: HashMap<CallKey, RuleTable>,
+    /// components whose fixpoint is being computed, and their current iteration
+    pub active: HashMap<usize, usize>,
+    /// components that got a new answer in the current iteration
+    pub changed: HashSet<usize>,
+}
+
+impl Default for RuleTables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RuleTables {
+    pub fn new() -> Self {
+        Self {
+            tables: HashMap::new(),
+            active: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
+
+    pub fn call_key(name: &str, args: &[Option<Value>]) -> CallKey {
+        (name.to_string(), args.to_vec())
+    }
+
+    pub fn add_answer(&mut self, key: &CallKey, answer: RuleAnswer) {
+        let table = self.tables.get_mut(key).unwrap();
+        if table.add(answer) {
+            let component = table.component;
+            self.changed.insert(component);
+        }
+    }
+
+    pub fn complete(&mut self, component: usize) {
+        for table in self.tables.values_mut() {
+            if table.component == component {
+                table.complete = true;
+            }
+        }
+        self.active.remove(&component);
+        self.changed.remove(&component);
+    }
+}
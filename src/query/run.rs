@@ -0,0 +1,77 @@
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::query::explain::Profile;
+use crate::query::rule::RuleTables;
+use crate::query::table::{Table, TextMap};
+
+/// Files used by a run, by path, `None` for files that do not exist
+pub type RunFiles<T> = RwLock<HashMap<String, Option<Arc<T>>>>;
+
+/// The state of one running query. Each query has its own, so that queries running at the same time
+/// never see each other's rule answers or counters
+pub struct QueryRun {
+    /// answers of the recursive rules called by this query
+    pub rule_tables: RwLock<RuleTables>,
+    /// counters of the query, when it runs with `profile`
+    pub profiler: Option<RwLock<Profile>>,
+    /// tables read by this query. Whether a file changed is only checked the first time the run uses it
+    pub tables: RunFiles<Table>,
+    /// text maps read by this query, see `tables`
+    pub text_maps: RunFiles<TextMap>,
+}
+
+impl QueryRun {
+    pub fn new() -> Self {
+        Self {
+            rule_tables: RwLock::new(RuleTables::new()),
+            profiler: None,
+            tables: RwLock::new(HashMap::new()),
+            text_maps: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// A run counting the frames, rows and file loads of each step
+    pub fn profiled() -> Self {
+        Self {
+            profiler: Some(RwLock::new(Profile::new())),
+            ..Self::new()
+        }
+    }
+
+    /// Whether steps may run on several threads. Not while profiling, as its counters follow one running step
+    pub fn parallel(&self) -> bool {
+        self.profiler.is_none()
+    }
+
+    /// Update the counters, if the query is profiled
+    pub fn record<T>(&self, f: impl FnOnce(&mut Profile) -> T) -> Option<T> {
+        self.profiler.as_ref().map(|x| f(&mut x.write()))
+    }
+
+    /// The file of `path` in `files`, read with `read` the first time the run uses it
+    pub fn file<T>(&self, files: &RunFiles<T>, path: &str, read: impl FnOnce() -> Option<(Arc<T>, bool)>) -> Option<Arc<T>> {
+        if let Some(x) = files.read().get(path) {
+            return x.clone();
+        }
+        let file = read().map(|(x, loaded)| {
+            if loaded {
+                self.record(|x| x.load());
+            }
+            x
+        });
+        files.write().insert(String::from(path), file.clone());
+        file
+    }
+
+    /// Take the counters collected so far
+    pub fn take_profile(&self) -> Profile {
+        self.profiler.as_ref().map(|x| std::mem::take(&mut *x.write())).unwrap_or_default()
+    }
+}
+
+impl Default for QueryRun {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::time::Instant;
+use crate::query::run::QueryRun;
+use crate::runtime::frame::Frame;
+
+/// Frames produced one at a time, as they are needed
+pub type Frames<'a> = Box<dyn Iterator<Item = Frame> + 'a>;
+
+/// the largest number of frames `Chunks` gathers at once
+const MAX_CHUNK: usize = 1024;
+/// rows or entries scanned by a step for one frame, above which the scan runs in parallel
+pub const PARALLEL_ROWS: usize = 4096;
+/// frames in a chunk, above which a predicate is evaluated for each of them in parallel
+pub const PARALLEL_FRAMES: usize = 64;
+
+/// Gathers frames into chunks of growing sizes, 1, 2, 4... up to `MAX_CHUNK`,
+/// so that the first results come early, while later frames are processed in bulk
+pub struct Chunks<'a> {
+    inner: Frames<'a>,
+    size: usize,
+}
+
+impl<'a> Chunks<'a> {
+    pub fn new(inner: Frames<'a>) -> Self {
+        Self {
+            inner,
+            size: 1,
+        }
+    }
+}
+
+impl Iterator for Chunks<'_> {
+    type Item = Vec<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk: Vec<Frame> = self.inner.by_ref().take(self.size).collect();
+        if chunk.is_empty() {
+            return None;
+        }
+        self.size = (self.size * 2).min(MAX_CHUNK);
+        Some(chunk)
+    }
+}
+
+/// Counts the frames a step produces, and the time spent producing them, when a query runs with `profile`
+pub struct Profiled<'a> {
+    run: Arc<QueryRun>,
+    step: usize,
+    inner: Frames<'a>,
+}
+
+impl<'a> Profiled<'a> {
+    pub fn new(run: Arc<QueryRun>, step: usize, inner: Frames<'a>) -> Self {
+        Self {
+            run,
+            step,
+            inner,
+        }
+    }
+}
+
+impl Iterator for Profiled<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.run.record(|x| x.enter(self.step));
+        let start = Instant::now();
+        let result = self.inner.next();
+        self.run.record(|x| x.exit(self.step, result.is_some(), start.elapsed()));
+        result
+    }
+}
@@ -0,0 +1,169 @@
+use indexmap::IndexMap;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use crate::ast::node::ast_expression::{ASTExpression, ExpressionType};
+use crate::file_system::file_system::FileSystem;
+use crate::runtime::accessor::{access_all, Accessor};
+use crate::runtime::value::{Value, ValueType};
+
+/// Values found at one path of every row of a table, by hash, with the index of their row.
+/// A row has several entries when the path has a wildcard
+pub type FieldIndex = HashMap<u64, Vec<(usize, Value)>>;
+
+/// Keys of a text map, by their text
+pub type TextIndex = HashMap<String, Vec<String>>;
+
+/// Rows of an Excel table, parsed once, with hash indexes on field paths built on demand.
+/// Rows are immutable and shared, frames binding a row refer to it rather than copy it
+pub struct Table {
+    pub rows: Vec<Value>,
+    indexes: RwLock<HashMap<Vec<Accessor>, Arc<FieldIndex>>>,
+}
+
+impl Table {
+    pub fn from_value(value: Value) -> Option<Self> {
+        let rows = match value.into_type() {
+            ValueType::List(x) => x,
+            _ => return None,
+        };
+        Some(Self {
+            rows,
+            indexes: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// The index on `path`, built the first time it is needed
+    pub fn index(&self, path: &[Accessor]) -> Arc<FieldIndex> {
+        if let Some(x) = self.indexes.read().get(path) {
+            return x.clone();
+        }
+
+        let mut index = FieldIndex::new();
+        for (i, row) in self.rows.iter().enumerate() {
+            for value in access_all(row, path).unwrap_or_default() {
+                index.entry(value.hash).or_default().push((i, value.clone()));
+            }
+        }
+
+        let index = Arc::new(index);
+        self.indexes.write().insert(path.to_vec(), index.clone());
+        index
+    }
+
+    /// Rows whose value at `path` equals `value`, with that value
+    pub fn lookup(&self, path: &[Accessor], value: &Value) -> Vec<(usize, Value)> {
+        let index = self.index(path);
+        let candidates = index.get(&value.hash).map(|x| x.as_slice()).unwrap_or_default();
+        candidates.iter().filter(|(_, v)| v == value).cloned().collect()
+    }
+}
+
+/// Entries of a text map, parsed once, with an index from texts to their keys built on demand
+pub struct TextMap {
+    /// keys and texts, in the order of the file, as parsed
+    pub entries: IndexMap<String, Value>,
+    reverse: OnceLock<TextIndex>,
+}
+
+impl TextMap {
+    /// Entries whose text is empty or not a string are left out, so that no query finds them
+    pub fn from_value(value: Value) -> Option<Self> {
+        let mut entries = match value.into_type() {
+            ValueType::Object(x) => x,
+            _ => return None,
+        };
+        entries.retain(|_, text| text.as_string().is_some_and(|x| !x.is_empty()));
+        Some(Self {
+            entries,
+            reverse: OnceLock::new(),
+        })
+    }
+
+    /// The text of `key`
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    /// Keys whose text is `text`
+    pub fn keys_of(&self, text: &str) -> Vec<String> {
+        let reverse = self.reverse.get_or_init(|| {
+            let mut reverse = TextIndex::new();
+            for (key, value) in self.entries.iter() {
+                if let Some(x) = value.as_string() {
+                    reverse.entry(String::from(x)).or_default().push(key.clone());
+                }
+            }
+            reverse
+        });
+        reverse.get(text).cloned().unwrap_or_default()
+    }
+}
+
+/// Parsed files with the version they were read at
+type Cache<T> = RwLock<HashMap<String, (Option<u64>, Arc<T>)>>;
+
+/// Tables and text maps of a `QueryProgram`, kept across queries until their file changes
+#[derive(Default)]
+pub struct TableCache {
+    tables: Cache<Table>,
+    text_maps: Cache<TextMap>,
+}
+
+impl TableCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The table in the file at `path`, and whether the file was read now, rather than found in the cache
+    pub fn get(&self, file_system: &dyn FileSystem, path: &str) -> Option<(Arc<Table>, bool)> {
+        cached(&self.tables, file_system, path, Table::from_value)
+    }
+
+    /// The text map in the file at `path`, and whether the file was read now
+    pub fn get_text_map(&self, file_system: &dyn FileSystem, path: &str) -> Option<(Arc<TextMap>, bool)> {
+        cached(&self.text_maps, file_system, path, TextMap::from_value)
+    }
+
+    /// The table of `path` if it was read before, without checking whether its file changed since
+    pub fn loaded(&self, path: &str) -> Option<Arc<Table>> {
+        self.tables.read().get(path).map(|(_, x)| x.clone())
+    }
+
+    /// The text map of `path` if it was read before, see `loaded`
+    pub fn loaded_text_map(&self, path: &str) -> Option<Arc<TextMap>> {
+        self.text_maps.read().get(path).map(|(_, x)| x.clone())
+    }
+}
+
+fn cached<T>(
+    cache: &Cache<T>,
+    file_system: &dyn FileSystem,
+    path: &str,
+    parse: impl FnOnce(Value) -> Option<T>,
+) -> Option<(Arc<T>, bool)> {
+    let version = file_system.version(path);
+    if let Some((v, x)) = cache.read().get(path) {
+        if *v == version {
+            return Some((x.clone(), false));
+        }
+    }
+
+    // the text is deserialized into `Value`s directly, and dropped once parsed
+    let content = file_system.read_uncached(path)?;
+    let parsed = Arc::new(parse(serde_json::from_str(&content).ok()?)?);
+    cache.write().insert(String::from(path), (version, parsed.clone()));
+    Some((parsed, true))
+}
+
+/// The first field of an object pattern whose value `known` gives, which a file query can look up in an index
+/// instead of scanning every row
+pub fn known_field<T>(ast: &ASTExpression, known: impl Fn(&ASTExpression) -> Option<T>) -> Option<(Vec<Accessor>, T)> {
+    match &ast.ast_type {
+        ExpressionType::ObjectPattern(o) => o.fields.iter().find_map(|(key, value)| {
+            let value = known(&value.read())?;
+            Some((vec![Accessor::Field(key.clone())], value))
+        }),
+        _ => None,
+    }
+}
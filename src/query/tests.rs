@@ -0,0 +1,953 @@
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::ast::node::ast_expression::ExpressionType;
+use crate::ast::parser::MyParser;
+use crate::file_system::file_system::FileSystem;
+use crate::query::error::QueryError;
+use crate::query::explain::PlanStep;
+use crate::query::query::QueryProgram;
+use crate::query::result::QueryResult;
+use crate::query::run::QueryRun;
+use crate::query::table::TableCache;
+use crate::runtime::accessor::Accessor;
+use crate::runtime::frame::Frame;
+use crate::runtime::value::{Value, ValueType};
+
+/// Files kept in memory, by path, with the number of times each was written as its version.
+/// Clones share the files, so that a test can change the files of a program
+#[derive(Clone, Default)]
+struct MemoryFileSystem {
+    files: Arc<RwLock<HashMap<String, (u64, String)>>>,
+}
+
+impl MemoryFileSystem {
+    fn write(&self, path: &str, content: &str) {
+        let mut files = self.files.write();
+        let version = files.get(path).map_or(0, |(v, _)| v + 1);
+        files.insert(String::from(path), (version, String::from(content)));
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn exists(&self, path: &str) -> bool {
+        self.files.read().contains_key(path)
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        self.files.read().get(path).map(|(_, x)| x.clone())
+    }
+
+    fn read_serde(&self, path: &str) -> Option<serde_json::Value> {
+        serde_json::from_str(&self.read(path)?).ok()
+    }
+
+    fn version(&self, path: &str) -> Option<u64> {
+        self.files.read().get(path).map(|(v, _)| *v)
+    }
+}
+
+/// The path of the Excel table `name`
+fn table_path(name: &str) -> String {
+    format!("ExcelBinOutput/{}.json", name)
+}
+
+/// The path of the text map of `locale`
+fn text_map_path(locale: &str) -> String {
+    format!("TextMap/TextMap{}.json", locale)
+}
+
+/// A program reading the Excel tables `tables`, given as names and JSON contents
+fn program(tables: &[(&str, &str)]) -> QueryProgram {
+    program_with_text_maps(tables, &[])
+}
+
+/// A program reading the Excel tables `tables` and the text maps `text_maps`, given as names and JSON contents
+fn program_with_text_maps(tables: &[(&str, &str)], text_maps: &[(&str, &str)]) -> QueryProgram {
+    let files = MemoryFileSystem::default();
+    for (name, json) in tables {
+        files.write(&table_path(name), json);
+    }
+    for (locale, json) in text_maps {
+        files.write(&text_map_path(locale), json);
+    }
+    QueryProgram::new(Box::new(files))
+}
+
+/// The answers of `q` as JSON rows of the given columns, in the order the query gives them
+fn ordered_answers(program: &QueryProgram, q: &str, columns: &[&str]) -> Vec<String> {
+    let result = program.try_query(q).unwrap();
+    result.to_serde_rows().iter()
+        .map(|row| {
+            let values: Vec<String> = columns.iter().map(|x| row[*x].to_string()).collect();
+            values.join(" ")
+        })
+        .collect()
+}
+
+/// The answers of `q` as JSON rows of the given columns, sorted, so that they can be compared in any order
+fn answers(program: &QueryProgram, q: &str, columns: &[&str]) -> Vec<String> {
+    let mut rows = ordered_answers(program, q, columns);
+    rows.sort();
+    rows
+}
+
+fn rows(expected: &[&str]) -> Vec<String> {
+    let mut rows: Vec<String> = expected.iter().map(|x| String::from(*x)).collect();
+    rows.sort();
+    rows
+}
+
+/// 1 -> 2 -> 3 -> 1 is a cycle, 4 -> 5 is not
+const CHAIN: &str = r#"[
+    {"id": 10, "from": 1, "to": 2},
+    {"id": 11, "from": 2, "to": 3},
+    {"id": 12, "from": 3, "to": 1},
+    {"id": 13, "from": 4, "to": 5}
+]"#;
+
+const NEXT: &str = "rule (next ?a ?b) { Chain.from ?x ?a && Chain.to ?x ?b }\n";
+
+#[test]
+fn recursion_terminates_on_cycles() {
+    let program = program(&[("Chain", CHAIN)]);
+    let q = format!("{}{}", NEXT, "
+        rule (reach ?a ?b) { next ?a ?b }
+        rule (reach ?a ?c) { reach ?a ?b && next ?b ?c }
+        reach 1 ?y");
+    assert_eq!(answers(&program, &q, &["?y"]), rows(&["1", "2", "3"]));
+    assert_eq!(answers(&program, "reach 4 ?y", &["?y"]), rows(&["5"]));
+    assert_eq!(answers(&program, "reach 5 ?y", &["?y"]), rows(&[]));
+}
+
+#[test]
+fn left_and_right_recursion_agree() {
+    let program = program(&[("Chain", CHAIN)]);
+    let q = format!("{}{}", NEXT, "
+        rule (left ?a ?b) { next ?a ?b }
+        rule (left ?a ?c) { left ?a ?b && next ?b ?c }
+        rule (right ?a ?b) { next ?a ?b }
+        rule (right ?a ?c) { next ?a ?b && right ?b ?c }
+        left ?x ?y");
+    let expected = rows(&[
+        "1 1", "1 2", "1 3",
+        "2 1", "2 2", "2 3",
+        "3 1", "3 2", "3 3",
+        "4 5",
+    ]);
+    assert_eq!(answers(&program, &q, &["?x", "?y"]), expected);
+    assert_eq!(answers(&program, "right ?x ?y", &["?x", "?y"]), expected);
+}
+
+#[test]
+fn recursion_with_bound_second_argument() {
+    let program = program(&[("Chain", CHAIN)]);
+    let q = format!("{}{}", NEXT, "
+        rule (reach ?a ?b) { next ?a ?b }
+        rule (reach ?a ?c) { reach ?a ?b && next ?b ?c }
+        reach ?x 5");
+    assert_eq!(answers(&program, &q, &["?x"]), rows(&["4"]));
+    assert_eq!(answers(&program, "reach ?x 2", &["?x"]), rows(&["1", "2", "3"]));
+}
+
+#[test]
+fn mutual_recursion() {
+    let line = r#"[
+        {"id": 0, "from": 0, "to": 1},
+        {"id": 1, "from": 1, "to": 2},
+        {"id": 2, "from": 2, "to": 3},
+        {"id": 3, "from": 3, "to": 4}
+    ]"#;
+    let program = program(&[("Chain", line)]);
+    let q = format!("{}{}", NEXT, "
+        rule (even ?a) { Chain.from ?x ?a && eq ?a 0 }
+        rule (even ?b) { odd ?a && next ?a ?b }
+        rule (odd ?b) { even ?a && next ?a ?b }
+        even ?x");
+    assert_eq!(answers(&program, &q, &["?x"]), rows(&["0", "2", "4"]));
+    assert_eq!(answers(&program, "odd ?x", &["?x"]), rows(&["1", "3"]));
+}
+
+#[test]
+fn negation_of_a_recursive_rule() {
+    let program = program(&[("Chain", CHAIN)]);
+    let q = format!("{}{}", NEXT, "
+        rule (reach ?a ?b) { next ?a ?b }
+        rule (reach ?a ?c) { reach ?a ?b && next ?b ?c }
+        Chain.from ?r ?x && !reach ?x 1");
+    assert_eq!(answers(&program, &q, &["?x"]), rows(&["4"]));
+}
+
+#[test]
+fn recursive_answers_are_not_kept_across_queries() {
+    let program = program(&[("Chain", CHAIN)]);
+    let q = format!("{}{}", NEXT, "
+        rule (reach ?a ?b) { next ?a ?b }
+        rule (reach ?a ?c) { reach ?a ?b && next ?b ?c }
+        reach 4 ?y");
+    assert_eq!(answers(&program, &q, &["?y"]), rows(&["5"]));
+    // a new alternative is used by the next query
+    program.try_query("rule (reach ?a ?b) { eq ?a 4 && eq ?b 6 }").unwrap();
+    assert_eq!(answers(&program, "reach 4 ?y", &["?y"]), rows(&["5", "6"]));
+}
+
+#[test]
+fn hash_join_matches_nested_join() {
+    let weapons: Vec<_> = (0..200).map(|i| serde_json::json!({"id": i, "level": i % 60})).collect();
+    let ranks: Vec<_> = (0..50).map(|i| serde_json::json!({"rank": format!("r{}", i), "from": i * 5})).collect();
+    let weapons = serde_json::to_string(&weapons).unwrap();
+    let ranks = serde_json::to_string(&ranks).unwrap();
+    let program = program(&[("Weapon", &weapons), ("Rank", &ranks)]);
+    let q = "Weapon.level ?w ?level && Rank.from ?r ?from && ge ?level ?from && Weapon.id ?w ?id && Rank.rank ?r ?rank";
+
+    // the first few weapons read the ranks each, the others are joined with the ranks read once
+    let (_, profile) = program.profile(q).unwrap();
+    let step = profile.steps.iter().find(|x| x.label == "Rank.from ?r ?from").unwrap();
+    assert!(step.input_frames < 10);
+
+    // the same answers as reading the ranks for each weapon
+    let mut expected: Vec<String> = (0..200)
+        .flat_map(|i| (0..50).filter(move |r| r * 5 <= i % 60).map(move |r| format!(r#"{} "r{}""#, i, r)))
+        .collect();
+    expected.sort();
+    assert_eq!(answers(&program, q, &["?id", "?rank"]), expected);
+}
+
+#[test]
+fn integers_are_exact_in_queries() {
+    let data = r#"[
+        {"id": 9007199254740993},
+        {"id": 9007199254740992},
+        {"id": 18446744073709551615}
+    ]"#;
+    let program = program(&[("Data", data)]);
+
+    // floats would not tell these apart
+    assert_eq!(answers(&program, "Data.id ?r 9007199254740993", &["?r"]), rows(&[r#"{"id":9007199254740993}"#]));
+    assert_eq!(answers(&program, "Data.id ?r ?id && gt ?id 9007199254740992", &["?id"]),
+               rows(&["9007199254740993", "18446744073709551615"]));
+    assert_eq!(answers(&program, "add 9007199254740993 1 ?x", &["?x"]), rows(&["9007199254740994"]));
+    // beyond 64 bits, and back
+    assert_eq!(answers(&program, "Data.id ?r ?id && gt ?id 18446744073709551614 && ?id + 1 == ?x && ?x - 1 == ?y", &["?y"]),
+               rows(&["18446744073709551615"]));
+    assert_eq!(answers(&program, "mul 18446744073709551615 2 ?x && ?x / 2 == ?y", &["?y"]),
+               rows(&["18446744073709551615"]));
+}
+
+#[test]
+fn results_beyond_64_bits_print_as_floats() {
+    let program = program(&[]);
+    let result = program.try_query("mul 18446744073709551615 2 ?x").unwrap();
+    assert_eq!(result.to_serde_rows()[0]["?x"], serde_json::json!(36893488147419103230.0));
+    let result = program.try_query("add 18446744073709551614 1 ?x").unwrap();
+    assert_eq!(result.to_serde_rows()[0]["?x"], serde_json::json!(18446744073709551615_u64));
+}
+
+#[test]
+fn text_map_keys_are_numbers() {
+    let en = r#"{"9": "nine", "10": "ten", "1001": "a thousand and one", "abc": "letters"}"#;
+    let program = program_with_text_maps(&[], &[("EN", en)]);
+
+    assert_eq!(answers(&program, "EN ?h ?x && ?h == 1001", &["?x"]), rows(&[r#""a thousand and one""#]));
+    assert_eq!(answers(&program, "EN ?h ?x && ?h >= 1001 && ?h <= 1001", &["?x"]), rows(&[r#""a thousand and one""#]));
+    assert_eq!(answers(&program, "EN ?h ?x && gt ?h 9", &["?x"]), rows(&[r#""ten""#, r#""a thousand and one""#]));
+    assert_eq!(answers(&program, "EN ?h ?x && ?h + 0 == ?k", &["?k"]), rows(&["9", "10", "1001"]));
+    assert_eq!(answers(&program, "sum ?s ?h { EN ?h ?x }", &["?s"]), rows(&["1020"]));
+    assert_eq!(ordered_answers(&program, "EN ?h ?x order by ?h", &["?x"]),
+               vec![r#""nine""#, r#""ten""#, r#""a thousand and one""#, r#""letters""#]);
+    assert_eq!(ordered_answers(&program, "EN ?h ?x order by ?h desc", &["?x"]),
+               vec![r#""letters""#, r#""a thousand and one""#, r#""ten""#, r#""nine""#]);
+}
+
+/// The access path of the step labelled `label` in the plan of `q`
+fn access_of(program: &QueryProgram, q: &str, label: &str) -> String {
+    fn find(step: &PlanStep, label: &str) -> Option<String> {
+        if step.label == label {
+            return step.access.clone();
+        }
+        step.children.iter().find_map(|x| find(x, label))
+    }
+    let plan = program.explain(q).unwrap().unwrap();
+    find(&plan, label).unwrap_or_else(|| panic!("no step {} in\n{}", label, plan))
+}
+
+#[test]
+fn hash_join_only_for_independent_scans() {
+    let program = program(&[("Big", "[]"), ("Small", "[]")]);
+
+    // `?id` is bound by the first step, so `Big` is looked up in an index for each frame
+    let q = "Big.g ?b 3 && Big.id ?b ?bid && Small.id ?c ?bid";
+    assert_eq!(access_of(&program, q, "Small.id ?c ?bid"), "lookup index on Small.id (10000 rows)");
+    let q = "Big.g ?b 3 && Big.id ?b ?id && Big.id ?c ?id";
+    assert_eq!(access_of(&program, q, "Big.id ?c ?id"), "lookup index on Big.id (10000 rows)");
+
+    // nothing is shared, the scan runs once and is joined
+    let q = "Big.g ?b 3 && Small.id ?c ?id && gt ?id 5";
+    assert_eq!(access_of(&program, q, "Small.id ?c ?id"), "hash join, scan file Small (10000 rows)");
+
+    // a rule called with a bound argument is evaluated for it
+    let q = "rule (small ?id) { Small.id ?c ?id } Big.g ?b 3 && Big.id ?b ?id && small ?id";
+    assert_eq!(access_of(&program, q, "small ?id"), "rule small");
+    let q = "rule (small ?id) { Small.id ?c ?id } Big.g ?b 3 && small ?x";
+    assert_eq!(access_of(&program, q, "small ?x"), "hash join, rule small");
+}
+
+#[test]
+fn rules_name_queries() {
+    let weapons = r#"[{"id": 1, "name": 101}, {"id": 2, "name": 102}]"#;
+    let en = r#"{"101": "Sword", "102": "Bow"}"#;
+    let program = program_with_text_maps(&[("Weapon", weapons)], &[("EN", en)]);
+    let q = "rule (weapon_name ?w ?name) { Weapon.name ?w ?hash && EN ?hash ?name }
+        Weapon.id ?w ?id && weapon_name ?w ?name";
+    assert_eq!(answers(&program, q, &["?id", "?name"]), rows(&[r#"1 "Sword""#, r#"2 "Bow""#]));
+
+    // kept for later queries, with variables local to the body
+    assert_eq!(answers(&program, r#"weapon_name ?x "Bow" && Weapon.id ?x ?hash"#, &["?hash"]), rows(&["2"]));
+    // alternatives yield the results of each
+    let q = r#"rule (weapon_name ?w ?name) { Weapon.id ?w 1 && eq ?name "Blade" } weapon_name ?w ?name && Weapon.id ?w 1"#;
+    assert_eq!(answers(&program, q, &["?name"]), rows(&[r#""Sword""#, r#""Blade""#]));
+}
+
+#[test]
+fn rule_calls_need_every_argument() {
+    let program = program(&[("Weapon", r#"[{"id": 1}]"#)]);
+    program.try_query("rule (id_of ?w ?id) { Weapon.id ?w ?id }").unwrap();
+
+    assert!(matches!(program.try_query("id_of ?w"),
+        Err(QueryError::RuleArity { rule, expected: 2, found: 1 }) if rule == "id_of"));
+    assert!(matches!(program.try_query("id_of ?w ?id ?x"),
+        Err(QueryError::RuleArity { expected: 2, found: 3, .. })));
+    assert!(program.query("id_of ?w").is_none());
+    assert_eq!(answers(&program, "id_of ?w ?id", &["?id"]), rows(&["1"]));
+}
+
+#[test]
+fn rule_bodies_bind_their_parameters() {
+    let program = program(&[("Weapon", r#"[{"id": 1}]"#)]);
+    let q = r#"rule (r ?a ?b) { Weapon.id ?w ?a } r ?x ?s && split_by ?p "a" ?q ?s"#;
+    assert!(matches!(program.try_query(q),
+        Err(QueryError::UnboundRuleParameter { rule, parameter }) if rule == "r" && parameter == "?b"));
+    // the call can still bind it
+    assert_eq!(answers(&program, "r ?x 5", &["?x"]), rows(&["1"]));
+
+    // a parameter the call binds needs no binding in the body
+    let q = "rule (big ?x) { gt ?x 0 } Weapon.id ?w ?id && big ?id";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["1"]));
+    assert!(matches!(program.try_query("big ?x"), Err(QueryError::InsufficientlyBound { .. })));
+    // every alternative must bind it
+    let q = "rule (id_or_any ?w ?id) { Weapon.id ?w ?id } rule (id_or_any ?w ?id) { Weapon ?w } id_or_any ?w ?id";
+    assert!(matches!(program.try_query(q), Err(QueryError::UnboundRuleParameter { parameter, .. }) if parameter == "?id"));
+}
+
+#[test]
+fn modifiers_apply_in_order() {
+    let weapons = r#"[
+        {"name": "a", "rank": 3, "type": 1},
+        {"name": "b", "rank": 5, "type": 2},
+        {"name": "c", "rank": 4, "type": 1},
+        {"name": "d", "rank": 1, "type": 2},
+        {"name": "e", "rank": 2, "type": 3}
+    ]"#;
+    let program = program(&[("Weapon", weapons)]);
+
+    // ordered by a variable that is not selected
+    let q = "select ?name where Weapon.name ?w ?name && Weapon.rank ?w ?rank order by ?rank desc";
+    assert_eq!(ordered_answers(&program, q, &["?name"]), vec![r#""b""#, r#""c""#, r#""a""#, r#""e""#, r#""d""#]);
+    assert_eq!(program.try_query(q).unwrap().columns, vec!["?name"]);
+
+    // distinct on the selected variables, then offset, then limit
+    let q = "select ?type where Weapon.type ?w ?type distinct order by ?type";
+    assert_eq!(ordered_answers(&program, q, &["?type"]), vec!["1", "2", "3"]);
+    let q = "select ?type where Weapon.type ?w ?type distinct order by ?type offset 1";
+    assert_eq!(ordered_answers(&program, q, &["?type"]), vec!["2", "3"]);
+    let q = "select ?type where Weapon.type ?w ?type distinct order by ?type limit 1 offset 1";
+    assert_eq!(ordered_answers(&program, q, &["?type"]), vec!["2"]);
+    let q = "select distinct ?type where Weapon.type ?w ?type";
+    assert!(matches!(program.try_query(q), Err(QueryError::Syntax)));
+    // without distinct, the offset counts repeated answers
+    let q = "select ?type where Weapon.type ?w ?type order by ?type limit 2 offset 1";
+    assert_eq!(ordered_answers(&program, q, &["?type"]), vec!["1", "2"]);
+
+    // ordered before the rows are paged
+    let q = "Weapon.name ?w ?name && Weapon.rank ?w ?rank order by ?rank limit 2";
+    assert_eq!(ordered_answers(&program, q, &["?name"]), vec![r#""d""#, r#""e""#]);
+    let q = "Weapon.name ?w ?name order by ?name desc offset 4";
+    assert_eq!(ordered_answers(&program, q, &["?name"]), vec![r#""a""#]);
+    let q = "Weapon.name ?w ?name offset 5";
+    assert!(ordered_answers(&program, q, &["?name"]).is_empty());
+}
+
+#[test]
+fn optional_binds_missing_fields_to_null() {
+    let talents = r#"[
+        {"id": 1, "nextId": 2, "name": "a"},
+        {"id": 2, "nextId": 3, "name": "b"},
+        {"id": 3, "name": "c"}
+    ]"#;
+    let program = program(&[("Talent", talents)]);
+
+    // the row without nextId is kept, with ?next bound to null
+    let q = "Talent.id ?t ?id && optional Talent.nextId ?t ?next";
+    assert_eq!(answers(&program, q, &["?id", "?next"]), rows(&["1 2", "2 3", "3 null"]));
+    assert_eq!(answers(&program, "Talent.id ?t ?id && Talent.nextId ?t ?next", &["?id"]), rows(&["1", "2"]));
+
+    // every variable of the inner query is bound to null together
+    let q = "Talent.id ?t ?id && optional (Talent.nextId ?t ?next && Talent.id ?n ?next && Talent.name ?n ?name)";
+    assert_eq!(answers(&program, q, &["?id", "?next", "?name"]), rows(&[r#"1 2 "b""#, r#"2 3 "c""#, "3 null null"]));
+
+    // a filter after the optional sees the null, which is not a number
+    let q = "Talent.id ?t ?id && optional Talent.nextId ?t ?next && ?next > 2";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["2"]));
+    let q = "Talent.id ?t ?id && optional Talent.nextId ?t ?next && !(?next > 2)";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["1", "3"]));
+}
+
+#[test]
+fn aggregates_by_group() {
+    let weapons = r#"[
+        {"id": 1, "type": "sword", "atk": 40},
+        {"id": 2, "type": "sword", "atk": 45},
+        {"id": 3, "type": "bow", "atk": 42},
+        {"id": 4, "type": "sword", "atk": 38},
+        {"id": 5, "type": "claymore"}
+    ]"#;
+    let program = program(&[("Weapon", weapons)]);
+
+    let q = "count ?n { Weapon ?w { type: ?type } } by ?type";
+    assert_eq!(answers(&program, q, &["?type", "?n"]), rows(&[r#""bow" 1"#, r#""claymore" 1"#, r#""sword" 3"#]));
+    let q = "sum ?s ?atk { Weapon ?w { type: ?type, atk: ?atk } } by ?type";
+    assert_eq!(answers(&program, q, &["?type", "?s"]), rows(&[r#""bow" 42"#, r#""sword" 123"#]));
+    let q = "min ?m ?atk { Weapon ?w { type: ?type, atk: ?atk } } by ?type";
+    assert_eq!(answers(&program, q, &["?type", "?m"]), rows(&[r#""bow" 42"#, r#""sword" 38"#]));
+    let q = "max ?m ?atk { Weapon ?w { type: ?type, atk: ?atk } } by ?type";
+    assert_eq!(answers(&program, q, &["?type", "?m"]), rows(&[r#""bow" 42"#, r#""sword" 45"#]));
+
+    // without `by`, a single result over every row
+    assert_eq!(answers(&program, "count ?n { Weapon ?w }", &["?n"]), rows(&["5"]));
+    assert_eq!(answers(&program, "max ?m ?atk { Weapon.atk ?w ?atk }", &["?m"]), rows(&["45"]));
+
+    // an aggregate after other conjuncts is computed for each of their results
+    let q = "Weapon.id ?w 1 && Weapon.type ?w ?type && count ?n { Weapon.type ?v ?type }";
+    assert_eq!(answers(&program, q, &["?n"]), rows(&["3"]));
+}
+
+#[test]
+fn aggregates_of_nothing() {
+    let program = program(&[("Weapon", r#"[{"id": 1, "type": "sword"}]"#)]);
+
+    // a single result without `by`, no group with it
+    assert_eq!(answers(&program, "count ?n { Weapon.type ?w \"bow\" }", &["?n"]), rows(&["0"]));
+    assert_eq!(answers(&program, "count ?n { Weapon.type ?w \"bow\" && Weapon.id ?w ?id } by ?id", &["?n"]), rows(&[]));
+    assert_eq!(answers(&program, "sum ?s ?id { Weapon.type ?w \"bow\" && Weapon.id ?w ?id } by ?id", &["?s"]), rows(&[]));
+}
+
+#[test]
+fn aggregates_in_recursion_are_rejected() {
+    let program = program(&[("Chain", CHAIN)]);
+    let q = format!("{}{}", NEXT, "
+        rule (deep ?a) { next ?a ?b }
+        rule (deep ?a) { count ?n { deep ?b } && next ?a ?n }
+        deep ?x");
+    assert!(matches!(program.try_query(&q), Err(QueryError::UnstratifiedNegation { rule, negated }) if rule == "deep" && negated == "deep"));
+    // none of the rules is kept
+    assert!(program.query("next ?a ?b").is_none());
+
+    let q = format!("{}{}", NEXT, "
+        rule (far ?a ?b) { next ?a ?b && optional far ?b ?c }
+        far 1 ?y");
+    assert!(matches!(program.try_query(&q), Err(QueryError::UnstratifiedNegation { .. })));
+}
+
+/// The answers of the conjunction `q` run in the order it is written, without planning, sorted
+fn unplanned_answers(program: &QueryProgram, q: &str, columns: &[&str]) -> Vec<String> {
+    let ast = MyParser.parsestring_expression(q).unwrap();
+    let frames: Vec<Frame> = match &ast.read().ast_type {
+        ExpressionType::AndExpression(a) => {
+            let start = Box::new(std::iter::once(Frame::new()));
+            program.query_and(&Arc::new(QueryRun::new()), start, a, 0).collect()
+        },
+        _ => panic!("not a conjunction"),
+    };
+    let result = QueryResult::new(columns.iter().map(|x| String::from(*x)).collect(), frames);
+    let mut rows: Vec<String> = result.to_serde_rows().iter()
+        .map(|row| {
+            let values: Vec<String> = columns.iter().map(|x| row[*x].to_string()).collect();
+            values.join(" ")
+        })
+        .collect();
+    rows.sort();
+    rows
+}
+
+/// The labels of the conjuncts of `q`, in the order the plan runs them
+fn planned_order(program: &QueryProgram, q: &str) -> Vec<String> {
+    let plan = program.explain(q).unwrap().unwrap();
+    plan.children.iter().map(|x| x.label.clone()).collect()
+}
+
+fn planner_program() -> QueryProgram {
+    let weapons: Vec<_> = (0..100).map(|i| serde_json::json!({"id": i, "type": i % 4, "rank": i % 5 + 1})).collect();
+    let refines: Vec<_> = (0..20).step_by(2).map(|i| serde_json::json!({"weapon": i, "level": i / 2})).collect();
+    let weapons = serde_json::to_string(&weapons).unwrap();
+    let refines = serde_json::to_string(&refines).unwrap();
+    program(&[("Weapon", &weapons), ("Refine", &refines), ("Banned", r#"[{"id": 3}, {"id": 8}, {"id": 13}]"#)])
+}
+
+#[test]
+fn planned_answers_match_the_written_order() {
+    let program = planner_program();
+
+    // the constant is looked up first, the negation and the built-ins still run after ?id is bound
+    let q = "Weapon.id ?w ?id && ?id < 20 && !Banned.id ?b ?id && add ?id 1000 ?key && Weapon.rank ?w 4";
+    assert_eq!(planned_order(&program, q)[0], "Weapon.rank ?w 4");
+    let expected = rows(&["18 1018"]);
+    assert_eq!(unplanned_answers(&program, q, &["?id", "?key"]), expected);
+    assert_eq!(answers(&program, q, &["?id", "?key"]), expected);
+
+    let q = "Weapon.id ?w ?id && Weapon.type ?w ?t && Weapon.rank ?w 2 && !(Banned.id ?b ?id || ?t == 3)";
+    assert_ne!(planned_order(&program, q)[0], "Weapon.id ?w ?id");
+    assert_eq!(answers(&program, q, &["?id", "?t"]), unplanned_answers(&program, q, &["?id", "?t"]));
+}
+
+#[test]
+fn planner_does_not_move_conjuncts_across_barriers() {
+    let program = planner_program();
+
+    // `Refine.level ?r ?lvl` filters out the nulls of the optional, so it must stay after it
+    let q = "Weapon.id ?w ?id && Weapon.type ?w 0 && optional Refine.weapon ?r ?id && Refine.level ?r ?lvl && Weapon.rank ?w 5";
+    let order = planned_order(&program, q);
+    assert_eq!(order[2], "optional");
+    assert!(order[..2].contains(&String::from("Weapon.type ?w 0")));
+    assert!(order[3..].contains(&String::from("Weapon.rank ?w 5")));
+    let expected = unplanned_answers(&program, q, &["?id", "?lvl"]);
+    assert_eq!(expected, rows(&["4 2"]));
+    assert_eq!(answers(&program, q, &["?id", "?lvl"]), expected);
+    let q = "Weapon.id ?w ?id && Weapon.type ?w 0 && Weapon.rank ?w 5 && optional Refine.weapon ?r ?id";
+    assert_eq!(answers(&program, q, &["?id", "?r"]), unplanned_answers(&program, q, &["?id", "?r"]));
+
+    // the count of each type is taken before the rank is fixed, as written
+    let q = "Weapon.type ?w ?t && count ?n { Weapon.type ?v ?t && Weapon.rank ?v 1 } && Weapon.rank ?w 1 && Weapon.id ?w 0";
+    assert_eq!(planned_order(&program, q)[1], "count ?n");
+    let expected = rows(&["0 5"]);
+    assert_eq!(unplanned_answers(&program, q, &["?t", "?n"]), expected);
+    assert_eq!(answers(&program, q, &["?t", "?n"]), expected);
+}
+
+#[test]
+fn planner_binds_variables_before_they_are_needed() {
+    let program = planner_program();
+
+    // written before what binds their variables, which the plan runs first
+    let q = "gt ?id 95 && !Banned.id ?b ?id && ?w.type == 0 && Weapon.id ?w ?id";
+    assert_eq!(planned_order(&program, q)[0], "Weapon.id ?w ?id");
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["96"]));
+
+    let q = "!Banned.id ?b ?id && ?id < 10 && Weapon.id ?w ?id && Weapon.type ?w 0";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["0", "4"]));
+    let q = "Weapon.id ?w ?id && Weapon.type ?w 0 && ?id < 10 && !Banned.id ?b ?id";
+    assert_eq!(unplanned_answers(&program, q, &["?id"]), rows(&["0", "4"]));
+}
+
+#[test]
+fn table_indexes_find_rows() {
+    let files = MemoryFileSystem::default();
+    let path = table_path("Weapon");
+    files.write(&path, r#"[
+        {"id": 1, "type": 1, "props": [{"id": 5}, {"id": 6}]},
+        {"id": 2, "type": 2, "props": [{"id": 6}]},
+        {"id": 3, "type": 1, "props": []}
+    ]"#);
+    let cache = TableCache::new();
+
+    let (table, loaded) = cache.get(&files, &path).unwrap();
+    assert!(loaded);
+    let type_path = vec![Accessor::Field(String::from("type"))];
+    let found: Vec<usize> = table.lookup(&type_path, &Value::from_int(1)).into_iter().map(|(i, _)| i).collect();
+    assert_eq!(found, vec![0, 2]);
+    assert!(table.lookup(&type_path, &Value::from_int(3)).is_empty());
+    // built once, then shared
+    assert!(Arc::ptr_eq(&table.index(&type_path), &table.index(&type_path)));
+
+    // a row has an entry for each element a wildcard reaches
+    let prop_path = vec![Accessor::Field(String::from("props")), Accessor::Wildcard, Accessor::Field(String::from("id"))];
+    let found: Vec<usize> = table.lookup(&prop_path, &Value::from_int(6)).into_iter().map(|(i, _)| i).collect();
+    assert_eq!(found, vec![0, 1]);
+    assert_eq!(table.index(&prop_path).values().map(|x| x.len()).sum::<usize>(), 3);
+
+    let (again, loaded) = cache.get(&files, &path).unwrap();
+    assert!(!loaded);
+    assert!(Arc::ptr_eq(&table, &again));
+}
+
+#[test]
+fn changed_tables_are_read_again() {
+    let files = MemoryFileSystem::default();
+    let path = table_path("Weapon");
+    files.write(&path, r#"[{"id": 1, "type": 1}, {"id": 2, "type": 2}]"#);
+    let cache = TableCache::new();
+    let type_path = vec![Accessor::Field(String::from("type"))];
+
+    let (old, _) = cache.get(&files, &path).unwrap();
+    assert_eq!(old.lookup(&type_path, &Value::from_int(2)).len(), 1);
+    files.write(&path, r#"[{"id": 1, "type": 2}, {"id": 2, "type": 2}, {"id": 3, "type": 2}]"#);
+    let (new, loaded) = cache.get(&files, &path).unwrap();
+    assert!(loaded);
+    assert_eq!(new.lookup(&type_path, &Value::from_int(2)).len(), 3);
+    // a run still holding the old table keeps its rows and index
+    assert_eq!(old.lookup(&type_path, &Value::from_int(2)).len(), 1);
+}
+
+/// The number of files `q` reads, rather than finds in the cache
+fn file_loads(program: &QueryProgram, q: &str) -> usize {
+    let (_, profile) = program.profile(q).unwrap();
+    profile.steps.iter().map(|x| x.file_loads).sum()
+}
+
+#[test]
+fn queries_see_changed_files() {
+    let files = MemoryFileSystem::default();
+    files.write(&table_path("Weapon"), r#"[{"id": 1, "type": 1}, {"id": 2, "type": 2}]"#);
+    let program = QueryProgram::new(Box::new(files.clone()));
+    let q = "Weapon.type ?w 2 && Weapon.id ?w ?id";
+    assert_eq!(access_of(&program, q, "Weapon.type ?w 2"), "lookup index on Weapon.type (10000 rows)");
+
+    assert_eq!(file_loads(&program, q), 1);
+    assert_eq!(file_loads(&program, q), 0);
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["2"]));
+
+    // the next run reads the file again, and looks up the rows in a new index
+    files.write(&table_path("Weapon"), r#"[{"id": 1, "type": 2}, {"id": 3, "type": 2}]"#);
+    assert_eq!(file_loads(&program, q), 1);
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["1", "3"]));
+    assert_eq!(file_loads(&program, q), 0);
+}
+
+#[test]
+fn text_maps_find_keys_by_text() {
+    let files = MemoryFileSystem::default();
+    let path = text_map_path("EN");
+    files.write(&path, r#"{"1": "Sword", "2": "Bow", "3": "Sword", "4": "", "5": 7}"#);
+    let cache = TableCache::new();
+
+    let (text_map, loaded) = cache.get_text_map(&files, &path).unwrap();
+    assert!(loaded);
+    assert_eq!(text_map.keys_of("Sword"), vec!["1", "3"]);
+    assert_eq!(text_map.keys_of("Bow"), vec!["2"]);
+    // empty and non-string texts are left out, of the entries and of the index
+    assert!(text_map.get("4").is_none());
+    assert!(text_map.get("5").is_none());
+    assert!(text_map.keys_of("").is_empty());
+    assert!(text_map.keys_of("Spear").is_empty());
+
+    let (again, loaded) = cache.get_text_map(&files, &path).unwrap();
+    assert!(!loaded);
+    assert!(Arc::ptr_eq(&text_map, &again));
+
+    // a changed file is read again, with a new index
+    files.write(&path, r#"{"1": "Bow", "3": "Sword", "6": "Sword"}"#);
+    let (new, loaded) = cache.get_text_map(&files, &path).unwrap();
+    assert!(loaded);
+    assert_eq!(new.keys_of("Sword"), vec!["3", "6"]);
+    assert_eq!(text_map.keys_of("Sword"), vec!["1", "3"]);
+}
+
+#[test]
+fn queries_look_up_texts_in_changed_text_maps() {
+    let files = MemoryFileSystem::default();
+    files.write(&text_map_path("EN"), r#"{"1": "Sword", "2": "Bow", "3": "Sword", "4": ""}"#);
+    let program = QueryProgram::new(Box::new(files.clone()));
+    let q = r#"EN ?h "Sword""#;
+    assert_eq!(access_of(&program, q, q), "lookup text in text map EN");
+
+    assert_eq!(answers(&program, q, &["?h"]), rows(&[r#""1""#, r#""3""#]));
+    assert_eq!(answers(&program, r#"EN ?h """#, &["?h"]), rows(&[]));
+    assert_eq!(file_loads(&program, q), 0);
+
+    files.write(&text_map_path("EN"), r#"{"2": "Sword", "3": "Bow"}"#);
+    assert_eq!(file_loads(&program, q), 1);
+    assert_eq!(answers(&program, q, &["?h"]), rows(&[r#""2""#]));
+    assert_eq!(answers(&program, r#"EN 3 ?text"#, &["?text"]), rows(&[r#""Bow""#]));
+}
+
+#[test]
+fn negation_filters_frames() {
+    let program = planner_program();
+
+    let q = "Weapon.id ?w ?id && ?id < 15 && !Banned.id ?b ?id";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["0", "1", "2", "4", "5", "6", "7", "9", "10", "11", "12", "14"]));
+    // `not` is the same as `!`, and a negated disjunction removes the answers of both sides
+    let q = "Weapon.id ?w ?id && ?id < 15 && not (Banned.id ?b ?id || Weapon.type ?w 0)";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["1", "2", "5", "6", "7", "9", "10", "11", "14"]));
+    // a negation of a negation keeps the frames with a result
+    let q = "Weapon.id ?w ?id && !!Banned.id ?b ?id";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["3", "8", "13"]));
+}
+
+#[test]
+fn negation_needs_its_shared_variables_bound() {
+    let program = planner_program();
+
+    // `?id` is shared by the negations, but neither binds it
+    let q = "!Banned.id ?b ?id && !Weapon.id ?w ?id";
+    assert!(matches!(program.try_query(q), Err(QueryError::UnboundInNegation { variable, rule: None }) if variable == "?id"));
+
+    // a parameter of a rule is shared with the caller, who may leave it unbound
+    let q = "rule (allowed ?id) { !Banned.id ?b ?id } allowed ?x";
+    assert!(matches!(program.try_query(q), Err(QueryError::UnboundInNegation { variable, rule: Some(rule) }) if variable == "?id" && rule == "allowed"));
+    // and safe when the caller binds it
+    let q = "Weapon.id ?w ?x && ?x < 5 && allowed ?x";
+    assert_eq!(answers(&program, q, &["?x"]), rows(&["0", "1", "2", "4"]));
+}
+
+#[test]
+fn infix_comparisons_and_arithmetic() {
+    let weapons = r#"[
+        {"id": 1, "rank": 3, "atk": 40},
+        {"id": 2, "rank": 4, "atk": 45},
+        {"id": 3, "rank": 5, "atk": 48},
+        {"id": 4, "rank": 5, "atk": 23}
+    ]"#;
+    let program = program(&[("Weapon", weapons)]);
+
+    let q = "Weapon.id ?w ?id && Weapon.rank ?w ?rank && ?rank >= 4";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["2", "3", "4"]));
+    // the hidden variables of intermediate results are not columns
+    let q = "Weapon.id ?w ?id && Weapon.atk ?w ?atk && ?atk * 2 > 90 + ?id";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["3"]));
+    assert_eq!(program.try_query(q).unwrap().columns, vec!["?w", "?id", "?atk"]);
+
+    // an unbound variable on one side of `==` is bound to the other side
+    let q = "Weapon.id ?w 2 && Weapon.atk ?w ?atk && (?atk - 5) / 2 == ?half";
+    assert_eq!(answers(&program, q, &["?half"]), rows(&["20"]));
+    let q = "Weapon.id ?w 1 && Weapon.atk ?w ?atk && ?atk / 3 == ?third";
+    assert_eq!(program.try_query(q).unwrap().to_serde_rows()[0]["?third"], serde_json::json!(40.0 / 3.0));
+
+    // comparisons inside negations and rule bodies
+    let q = "Weapon.id ?w ?id && Weapon.rank ?w ?rank && !(?rank != 5)";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["3", "4"]));
+    let q = "rule (strong ?w) { Weapon.atk ?w ?atk && ?atk > 44 } strong ?w && Weapon.id ?w ?id";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["2", "3"]));
+
+    // a comparison of an unbound variable cannot run
+    assert!(matches!(program.try_query("?x > 1"), Err(QueryError::InsufficientlyBound { .. })));
+}
+
+/// Weapons with nested objects and arrays
+const NESTED: &str = r#"[
+    {"id": 1, "name": 101, "info": {"rank": 4, "tags": ["a", "b"]}, "props": [{"type": "ATK", "value": 40}, {"type": "CRIT", "value": 5}]},
+    {"id": 2, "name": 102, "info": {"rank": 5, "tags": []}, "props": [{"type": "ATK", "value": 45}]},
+    {"id": 3, "name": 103, "props": []}
+]"#;
+
+const NESTED_EN: &str = r#"{"101": "Sword", "102": "Bow", "103": "Spear"}"#;
+
+#[test]
+fn paths_read_fields_of_bound_variables() {
+    let program = program_with_text_maps(&[("Weapon", NESTED)], &[("EN", NESTED_EN)]);
+
+    assert_eq!(answers(&program, "Weapon ?w && EN ?w.name ?name", &["?name"]), rows(&[r#""Sword""#, r#""Bow""#, r#""Spear""#]));
+    assert_eq!(answers(&program, "Weapon ?w && eq ?w.info.rank ?rank", &["?rank"]), rows(&["4", "5"]));
+    assert_eq!(answers(&program, "Weapon ?w && eq ?w.props[1].type ?t", &["?t"]), rows(&[r#""CRIT""#]));
+    assert_eq!(answers(&program, "Weapon ?w && ?w.info.rank > 4 && eq ?w.id ?id", &["?id"]), rows(&["2"]));
+    // a path to nothing gives no answer
+    assert_eq!(answers(&program, "Weapon ?w && eq ?w.missing ?x", &["?x"]), rows(&[]));
+    assert_eq!(answers(&program, "Weapon ?w && eq ?w.id.more ?x", &["?x"]), rows(&[]));
+
+    // the variable is bound by a later conjunct, which the plan runs first
+    assert_eq!(answers(&program, "EN ?w.name \"Bow\" && Weapon ?w", &["?w"]).len(), 1);
+    assert!(matches!(program.try_query("EN ?w.name ?name"), Err(QueryError::UnboundPath { variable, rule: None }) if variable == "?w"));
+}
+
+#[test]
+fn predicates_index_and_iterate_arrays() {
+    let program = program(&[("Weapon", NESTED)]);
+
+    // each element reached by `[*]` gives its own answer
+    let q = "Weapon.props[*].type ?w ?t && Weapon.id ?w ?id";
+    assert_eq!(answers(&program, q, &["?id", "?t"]), rows(&[r#"1 "ATK""#, r#"1 "CRIT""#, r#"2 "ATK""#]));
+    assert_eq!(answers(&program, "Weapon.info.tags[*] ?w ?tag", &["?tag"]), rows(&[r#""a""#, r#""b""#]));
+    // an index out of range gives no answer
+    let q = "Weapon.props[0].value ?w ?v && Weapon.id ?w ?id";
+    assert_eq!(answers(&program, q, &["?id", "?v"]), rows(&["1 40", "2 45"]));
+    assert_eq!(answers(&program, "Weapon.props[1].value ?w ?v", &["?v"]), rows(&["5"]));
+    assert_eq!(answers(&program, "Weapon.props[2].value ?w ?v", &["?v"]), rows(&[]));
+    // without an accessor, the array is a single value
+    assert_eq!(answers(&program, "Weapon.info.tags ?w ?tags", &["?tags"]), rows(&[r#"["a","b"]"#, "[]"]));
+
+    // a known value is found by any element
+    assert_eq!(answers(&program, "Weapon.props[*].type ?w \"CRIT\" && Weapon.id ?w ?id", &["?id"]), rows(&["1"]));
+    assert_eq!(answers(&program, "Weapon.props[*].value ?w ?v && ?v > 10", &["?v"]), rows(&["40", "45"]));
+}
+
+#[test]
+fn object_patterns_match_rows() {
+    let program = program(&[("Weapon", NESTED)]);
+
+    let q = "Weapon { id: ?id, info: { rank: 5 } }";
+    assert_eq!(answers(&program, q, &["?id"]), rows(&["2"]));
+    let q = "Weapon ?w { name: ?name, info: { rank: ?rank } }";
+    assert_eq!(answers(&program, q, &["?name", "?rank"]), rows(&["101 4", "102 5"]));
+    // a missing field does not match, and string keys are the same as identifiers
+    assert_eq!(answers(&program, r#"Weapon { "id": ?id, info: ?info }"#, &["?id"]), rows(&["1", "2"]));
+    // a variable used twice must match the same value
+    let data = r#"[{"a": 1, "b": 1}, {"a": 1, "b": 2}]"#;
+    let program = program_with_text_maps(&[("Data", data), ("Weapon", NESTED)], &[]);
+    assert_eq!(answers(&program, "Data ?r { a: ?x, b: ?x }", &["?r"]), rows(&[r#"{"a":1,"b":1}"#]));
+    // a pattern with a bound variable only matches its value, a field path gives the field to match
+    let q = "Data { a: ?x, b: 2 } && Weapon { id: ?x, name: ?name }";
+    assert_eq!(answers(&program, q, &["?name"]), rows(&["101"]));
+    assert_eq!(answers(&program, "Weapon.info ?w { rank: ?rank }", &["?rank"]), rows(&["4", "5"]));
+}
+
+#[test]
+fn explain_shows_the_plan() {
+    let program = program_with_text_maps(&[("Weapon", NESTED)], &[("EN", NESTED_EN)]);
+    let q = "Weapon.id ?w ?id && ?id < 3 && EN ?w.name ?name && !(Weapon.info.rank ?w 5) || count ?n { Weapon ?x }";
+    let expected = "\
+||  [~2501 rows]
+  &&  [~2500 rows]
+    Weapon.id ?w ?id  [scan file Weapon (10000 rows), ~10000 rows]
+    lt ?id 3  [built-in lt, ~5000 rows]
+    !  [~2500 rows]
+      Weapon.info.rank ?w 5  [lookup row in Weapon, ~500 rows]
+    EN ?w.name ?name  [lookup key in text map EN, ~2500 rows]
+  count ?n  [~1.0 rows]
+    Weapon ?x  [scan file Weapon (10000 rows), ~10000 rows]
+";
+    assert_eq!(program.explain(q).unwrap().unwrap().to_string(), expected);
+    // explaining reads no file
+    assert_eq!(file_loads(&program, "Weapon ?w"), 1);
+
+    // the rules of an explained program are not kept
+    let q = "rule (named ?w) { EN ?w.name ?name } Weapon ?w && named ?w";
+    assert_eq!(access_of(&program, q, "named ?w"), "rule named");
+    assert!(program.query("Weapon ?w && named ?w").is_none());
+    assert!(program.explain("rule (named ?w) { Weapon ?w }").unwrap().is_none());
+    assert!(matches!(program.explain("Weapon ?w order by ?x"), Err(QueryError::UnknownVariable { .. })));
+}
+
+#[test]
+fn profile_counts_each_step() {
+    let program = program_with_text_maps(&[("Weapon", NESTED)], &[("EN", NESTED_EN)]);
+    let q = "Weapon.id ?w ?id && ?id < 3 && EN ?w.name ?name && !(Weapon.info.rank ?w 5)";
+    let (result, profile) = program.profile(q).unwrap();
+    assert_eq!(result.to_serde_rows().len(), 1);
+
+    let step = |label: &str| profile.steps.iter().find(|x| x.label == label).unwrap().clone();
+    let scan = step("Weapon.id ?w ?id");
+    assert_eq!((scan.input_frames, scan.output_frames, scan.rows_scanned, scan.file_loads), (1, 3, 3, 1));
+    let negation = step("!");
+    assert_eq!((negation.input_frames, negation.output_frames), (2, 1));
+    let filter = step("lt ?id 3");
+    assert_eq!((filter.input_frames, filter.output_frames, filter.rows_scanned), (3, 2, 0));
+    // one lookup in the text map, for the only frame left
+    let lookup = step("EN ?w.name ?name");
+    assert_eq!((lookup.input_frames, lookup.output_frames, lookup.rows_scanned, lookup.file_loads), (1, 1, 1, 1));
+    assert!(lookup.depth > 0);
+
+    // a line per step, indented by its depth
+    let text = profile.to_string();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], " calls        in       out    scanned loads       time  step");
+    assert_eq!(lines.len(), profile.steps.len() + 1);
+    for (line, step) in lines[1..].iter().zip(profile.steps.iter()) {
+        assert!(line.ends_with(&format!("ms  {}{}", "  ".repeat(step.depth), step.label)), "{}", line);
+    }
+
+    // the files are cached for the next run
+    let (_, profile) = program.profile(q).unwrap();
+    assert_eq!(profile.steps.iter().map(|x| x.file_loads).sum::<usize>(), 0);
+}
+
+#[test]
+fn bound_variables_are_looked_up() {
+    let en: serde_json::Map<String, serde_json::Value> = (100..200).map(|i| (i.to_string(), serde_json::json!(format!("text {}", i % 50)))).collect();
+    let en = serde_json::to_string(&en).unwrap();
+    let refines: Vec<_> = (0..100).map(|i| serde_json::json!({"weapon": i % 10, "level": i})).collect();
+    let refines = serde_json::to_string(&refines).unwrap();
+    let program = program_with_text_maps(&[("Weapon", NESTED), ("Refine", &refines)], &[("EN", &en)]);
+
+    // the key of the text map is bound by the first step, so each frame reads a single entry
+    let q = "Weapon.name ?w ?h && EN ?h ?text";
+    assert_eq!(access_of(&program, q, "EN ?h ?text"), "lookup key in text map EN");
+    assert_eq!(answers(&program, q, &["?text"]), rows(&[r#""text 1""#, r#""text 2""#, r#""text 3""#]));
+    let (_, profile) = program.profile(q).unwrap();
+    let step = profile.steps.iter().find(|x| x.label == "EN ?h ?text").unwrap();
+    assert_eq!((step.input_frames, step.rows_scanned), (3, 3));
+
+    // likewise for a bound text, which is found in the reverse index
+    let q = "Weapon.name ?w ?h && EN ?h ?text && EN ?other ?text";
+    assert_eq!(access_of(&program, q, "EN ?other ?text"), "lookup text in text map EN");
+    assert_eq!(answers(&program, q, &["?h", "?other"]).len(), 6);
+    let (_, profile) = program.profile(q).unwrap();
+    let step = profile.steps.iter().find(|x| x.label == "EN ?other ?text").unwrap();
+    assert_eq!((step.input_frames, step.output_frames, step.rows_scanned), (3, 6, 6));
+
+    // and for a field of a file, which is found in the index of the field
+    let q = "Weapon.id ?w ?id && Refine.weapon ?r ?id && Refine.level ?r ?level";
+    assert_eq!(access_of(&program, q, "Refine.weapon ?r ?id"), "lookup index on Refine.weapon (10000 rows)");
+    assert_eq!(answers(&program, q, &["?level"]).len(), 30);
+    let (_, profile) = program.profile(q).unwrap();
+    let step = profile.steps.iter().find(|x| x.label == "Refine.weapon ?r ?id").unwrap();
+    assert_eq!((step.input_frames, step.output_frames, step.rows_scanned), (3, 30, 30));
+
+    // a bound row is read by itself, rather than looked up by the value of its field
+    let q = "Refine.level ?r ?level && ?level < 40 && !Refine.weapon ?r 3";
+    assert_eq!(access_of(&program, q, "Refine.weapon ?r 3"), "lookup row in Refine");
+    assert_eq!(answers(&program, q, &["?level"]).len(), 36);
+    let (_, profile) = program.profile(q).unwrap();
+    let step = profile.steps.iter().find(|x| x.label == "Refine.weapon ?r 3").unwrap();
+    assert_eq!((step.input_frames, step.output_frames, step.rows_scanned), (40, 4, 40));
+}
+
+#[test]
+fn parallel_evaluation_keeps_the_order() {
+    // enough rows to scan in parallel blocks, and frames to evaluate in parallel chunks
+    let rows_count = 10000;
+    let weapons: Vec<_> = (0..rows_count).map(|i| serde_json::json!({"id": i, "type": i % 3})).collect();
+    let weapons = serde_json::to_string(&weapons).unwrap();
+    let program = program(&[("Weapon", &weapons)]);
+
+    // rows in the order of the file
+    let q = "Weapon.id ?w ?id";
+    let expected: Vec<String> = (0..rows_count).map(|i| i.to_string()).collect();
+    assert_eq!(ordered_answers(&program, q, &["?id"]), expected);
+
+    // for each frame, the answers of the left side of `||`, then those of the right side
+    let q = "Weapon.id ?w ?id && (Weapon.type ?w 0 && eq ?x \"zero\" || Weapon.type ?w ?t && ?t < 2 && eq ?x ?t)";
+    let expected: Vec<String> = (0..rows_count)
+        .flat_map(|i| match i % 3 {
+            0 => vec![format!(r#"{} "zero""#, i), format!("{} 0", i)],
+            1 => vec![format!("{} 1", i)],
+            _ => vec![],
+        })
+        .collect();
+    assert_eq!(ordered_answers(&program, q, &["?id", "?x"]), expected);
+
+    // the same as the sequential evaluation of a profiled run
+    let (result, _) = program.profile(q).unwrap();
+    let sequential: Vec<String> = result.to_serde_rows().iter().map(|row| format!("{} {}", row["?id"], row["?x"])).collect();
+    assert_eq!(sequential, expected);
+}
+
+#[test]
+fn frames_share_the_rows_of_tables() {
+    let program = program(&[("Weapon", NESTED)]);
+    let value = |frames: &[Frame], variable: &str| frames[0].get(variable).unwrap().clone();
+
+    // the same row in two runs, and in two steps of a run
+    let first = program.query("Weapon.id ?w 1").unwrap();
+    let second = program.query("Weapon.id ?w 1 && Weapon ?v && eq ?v.id 1").unwrap();
+    assert!(Arc::ptr_eq(&value(&first, "?w").value_type, &value(&second, "?w").value_type));
+    assert!(Arc::ptr_eq(&value(&second, "?w").value_type, &value(&second, "?v").value_type));
+
+    // a field bound by a predicate or a path is the one in the row, not a copy
+    let frames = program.query("Weapon.id ?w 1 && Weapon.props ?w ?props && eq ?w.info ?info").unwrap();
+    let row = value(&frames, "?w");
+    let field = |name: &str| match row.value_type.as_ref() {
+        ValueType::Object(x) => x[name].clone(),
+        _ => panic!("not an object"),
+    };
+    assert!(Arc::ptr_eq(&value(&frames, "?props").value_type, &field("props").value_type));
+    assert!(Arc::ptr_eq(&value(&frames, "?info").value_type, &field("info").value_type));
+}
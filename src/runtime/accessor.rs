@@ -0,0 +1,46 @@
+use std::fmt::{Display, Formatter};
+use crate::runtime::value::{Value, ValueType};
+
+/// One step of a path into a value, for example, the `id` in `?x.id`, or the `[0]` in `weaponProp[0]`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Accessor {
+    Field(String),
+    Index(usize),
+    /// every element of an array, `[*]`
+    Wildcard,
+}
+
+impl Display for Accessor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Accessor::Field(name) => write!(f, ".{}", name),
+            Accessor::Index(i) => write!(f, "[{}]", i),
+            Accessor::Wildcard => write!(f, "[*]"),
+        }
+    }
+}
+
+/// Follow `path` from `v`, `None` if some step does not exist.
+/// A wildcard in the path yields the first element of the array
+pub fn access<'a>(v: &'a Value, path: &[Accessor]) -> Option<&'a Value> {
+    access_all(v, path)?.into_iter().next()
+}
+
+/// Follow `path` from `v`, a wildcard fans out to every element of the array.
+/// `None` if some step outside of a wildcard does not exist, elements of a wildcard where the rest of
+/// the path does not exist are skipped
+pub fn access_all<'a>(v: &'a Value, path: &[Accessor]) -> Option<Vec<&'a Value>> {
+    let (first, rest) = match path.split_first() {
+        Some(x) => x,
+        None => return Some(vec![v]),
+    };
+
+    match (first, v.value_type.as_ref()) {
+        (Accessor::Field(name), ValueType::Object(map)) => access_all(map.get(name)?, rest),
+        (Accessor::Index(i), ValueType::List(arr)) => access_all(arr.get(*i)?, rest),
+        (Accessor::Wildcard, ValueType::List(arr)) => {
+            Some(arr.iter().filter_map(|x| access_all(x, rest)).flatten().collect())
+        },
+        _ => None,
+    }
+}
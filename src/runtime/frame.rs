@@ -1,21 +1,32 @@
-use std::collections::HashMap;
-use crate::runtime::value::{NonVariableValue, Value};
+use im::OrdMap;
+use crate::runtime::name::Name;
+use crate::runtime::value::Value;
 
 #[derive(Debug, Clone)]
 pub enum ConstraintTarget {
-    Variable(String),
+    Variable(Name),
     NonVariable(Value),
 }
 
+/// Variables bound by a query so far.
+///
+/// The map is persistent and values are reference counted, so cloning a frame is cheap, and a frame extended
+/// with a new variable shares everything else with the frame it came from
 #[derive(Debug, Clone)]
 pub struct Frame {
-    pub constraints: HashMap<String, ConstraintTarget>,
+    pub constraints: OrdMap<Name, ConstraintTarget>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Frame {
     pub fn new() -> Self {
         Frame {
-            constraints: HashMap::new(),
+            constraints: OrdMap::new(),
         }
     }
 
@@ -31,14 +42,35 @@ impl Frame {
         self.constraints.contains_key(name)
     }
 
-    pub fn add(&mut self, name: &str, value: Value) {
-        self.constraints.insert(String::from(name), ConstraintTarget::NonVariable(value));
+    pub fn add(&mut self, name: impl Into<Name>, value: Value) {
+        self.constraints.insert(name.into(), ConstraintTarget::NonVariable(value));
     }
 
-    pub fn add_serde(&mut self, name: &str, value: serde_json::Value) {
-        let v = NonVariableValue::from_serde(value).to_value();
+    pub fn add_serde(&mut self, name: impl Into<Name>, value: serde_json::Value) {
+        let v = Value::from_serde(value);
         let target = ConstraintTarget::NonVariable(v);
-        self.constraints.insert(String::from(name), target);
+        self.constraints.insert(name.into(), target);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.constraints.remove(name);
+    }
+
+    /// Keep only the variables in `names`
+    pub fn retain(&mut self, names: &[String]) {
+        self.remove_where(|k| !names.iter().any(|x| x == k));
+    }
+
+    /// Remove variables whose name starts with `prefix`
+    pub fn remove_prefixed(&mut self, prefix: &str) {
+        self.remove_where(|k| k.starts_with(prefix));
+    }
+
+    fn remove_where(&mut self, f: impl Fn(&str) -> bool) {
+        let removed: Vec<Name> = self.constraints.keys().filter(|k| f(k)).cloned().collect();
+        for k in removed.iter() {
+            self.constraints.remove(k);
+        }
     }
 
     pub fn is_resolved(&self) -> bool {
@@ -50,12 +82,12 @@ impl Frame {
         true
     }
 
+    /// All bound variables as a JSON object, sorted by name
     pub fn to_serde_map(&self) -> serde_json::Value {
         let mut result = serde_json::Map::new();
-        for k in self.constraints.keys() {
-            let v = self.constraints.get(k.as_str()).unwrap();
+        for (k, v) in self.constraints.iter() {
             if let ConstraintTarget::NonVariable(x) = v {
-                result.insert(k.clone(), x.get_serde_value().clone());
+                result.insert(k.to_string(), x.to_serde());
             }
         }
 
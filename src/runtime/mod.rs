@@ -1,2 +1,4 @@
 pub mod value;
 pub mod frame;
+pub mod accessor;
+pub mod name;
@@ -0,0 +1,130 @@
+use parking_lot::RwLock;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::sync::{Arc, LazyLock, Weak};
+
+/// Names in use, so that each name is only allocated once while it is used.
+/// Names no longer used by any query or rule are dropped, as each query brings new ones
+static NAMES: LazyLock<RwLock<Names>> = LazyLock::new(|| RwLock::new(Names::default()));
+
+/// Entries below which the names are never purged
+const MIN_PURGE: usize = 1024;
+
+#[derive(Default)]
+struct Names {
+    entries: HashMap<Box<str>, Weak<str>>,
+    /// number of entries after the last purge, which runs again once they double
+    purged: usize,
+}
+
+impl Names {
+    fn get(&self, name: &str) -> Option<Arc<str>> {
+        self.entries.get(name).and_then(|x| x.upgrade())
+    }
+
+    fn insert(&mut self, name: &str) -> Arc<str> {
+        if self.entries.len() >= MIN_PURGE.max(self.purged * 2) {
+            self.entries.retain(|_, x| x.strong_count() > 0);
+            self.purged = self.entries.len();
+        }
+        let x: Arc<str> = Arc::from(name);
+        self.entries.insert(Box::from(name), Arc::downgrade(&x));
+        x
+    }
+}
+
+/// An interned variable name. Equal names in use share one allocation, so cloning a name is cheap
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Name(Arc<str>);
+
+impl Name {
+    pub fn new(name: &str) -> Self {
+        if let Some(x) = NAMES.read().get(name) {
+            return Name(x);
+        }
+
+        let mut names = NAMES.write();
+        match names.get(name) {
+            Some(x) => Name(x),
+            None => Name(names.insert(name)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Self {
+        Name::new(name)
+    }
+}
+
+impl From<&String> for Name {
+    fn from(name: &String) -> Self {
+        Name::new(name)
+    }
+}
+
+impl From<String> for Name {
+    fn from(name: String) -> Self {
+        Name::new(&name)
+    }
+}
+
+impl From<&Name> for Name {
+    fn from(name: &Name) -> Self {
+        name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_names_share_an_allocation() {
+        let a = Name::new("?shared_name");
+        let b = Name::from(String::from("?shared_name"));
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a.as_str(), "?shared_name");
+    }
+
+    #[test]
+    fn unused_names_are_dropped() {
+        // names of queries that are done, like the hidden variables of each query
+        for i in 0..10 * MIN_PURGE {
+            Name::new(&format!("?#unused{}", i));
+        }
+        let kept = Name::new("?kept_name");
+        for i in 0..10 * MIN_PURGE {
+            Name::new(&format!("?#unused_again{}", i));
+        }
+        // other tests may add names at the same time, but far fewer
+        assert!(NAMES.read().entries.len() < 5 * MIN_PURGE);
+        assert!(Arc::ptr_eq(&kept.0, &Name::new("?kept_name").0));
+    }
+}
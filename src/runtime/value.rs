@@ -1,15 +1,69 @@
+use indexmap::IndexMap;
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use std::cmp::Ordering;
 use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
+/// The content of a value.
+///
+/// Types are only converted explicitly:
+/// - `Int` and `Float` are both numbers. `as_number` reads either as a float, and `compare_numbers`
+///   and arithmetic keep integers exact. A string written like an integer, like a text map key, is read as that integer
+/// - `as_text` reads strings and numbers as text, for text maps, `split_by` and `regex`
+/// - equality, see `PartialEq`, treats a string written like an integer as that integer,
+///   so text map keys and the hashes in files join. Other types never equal each other
 #[derive(Debug, Clone)]
-pub struct NonVariableValue {
-    pub v: serde_json::Value,
+pub enum ValueType {
+    Null,
+    Bool(bool),
+    /// wide enough for every integer of a JSON file, signed or not
+    Int(i128),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    Object(IndexMap<String, Value>),
 }
 
-impl NonVariableValue {
+impl ValueType {
+    /// Integers are kept exact, other numbers become floats
     pub fn from_serde(value: serde_json::Value) -> Self {
-        Self {
-            v: value
+        match value {
+            serde_json::Value::Null => ValueType::Null,
+            serde_json::Value::Bool(b) => ValueType::Bool(b),
+            serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+                (Some(i), _) => ValueType::Int(i as i128),
+                (None, Some(u)) => ValueType::Int(u as i128),
+                _ => ValueType::Float(n.as_f64().unwrap()),
+            },
+            serde_json::Value::String(s) => ValueType::String(s),
+            serde_json::Value::Array(arr) => ValueType::List(arr.into_iter().map(Value::from_serde).collect()),
+            serde_json::Value::Object(map) => {
+                ValueType::Object(map.into_iter().map(|(k, v)| (k, Value::from_serde(v))).collect())
+            },
+        }
+    }
+
+    /// Floats that JSON cannot represent, infinities and NaN, become null.
+    /// Integers beyond 64 bits, which only arithmetic gives, become floats
+    pub fn to_serde(&self) -> serde_json::Value {
+        match self {
+            ValueType::Null => serde_json::Value::Null,
+            ValueType::Bool(b) => serde_json::Value::Bool(*b),
+            ValueType::Int(i) => match (i64::try_from(*i), u64::try_from(*i)) {
+                (Ok(x), _) => serde_json::json!(x),
+                (_, Ok(x)) => serde_json::json!(x),
+                _ => ValueType::Float(*i as f64).to_serde(),
+            },
+            ValueType::Float(f) => serde_json::Number::from_f64(*f)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            ValueType::String(s) => serde_json::Value::String(s.clone()),
+            ValueType::List(arr) => serde_json::Value::Array(arr.iter().map(|x| x.to_serde()).collect()),
+            ValueType::Object(map) => {
+                serde_json::Value::Object(map.iter().map(|(k, v)| (k.clone(), v.to_serde())).collect())
+            },
         }
     }
 
@@ -18,87 +72,377 @@ impl NonVariableValue {
         self.hash(&mut hasher);
         let h = hasher.finish();
         Value {
-            value_type: self,
+            value_type: Arc::new(self),
             hash: h
         }
     }
 }
 
-fn hash_serde_value<H: Hasher>(state: &mut H, value: &serde_json::Value) {
-    match value {
-        serde_json::Value::Object(map) => {
-            let mut keys: Vec<_> = map.keys().collect();
-            keys.sort();
-            for &k in keys.iter() {
-                k.hash(state);
-                let value = map.get(k.as_str()).unwrap();
-                hash_serde_value(state, value);
-            }
-        },
-        serde_json::Value::Array(arr) => {
-            for item in arr {
-                hash_serde_value(state, item);
-            }
-        },
-        serde_json::Value::String(s) => s.hash(state),
-        serde_json::Value::Number(n) => {
-            let f = n.as_f64().unwrap();
-            if f.fract().abs() < 1e-6 {
-                // is an integer, convert to string and hash
-                let s = f64::to_string(&f.trunc());
-                s.hash(state);
-            } else {
-                // the precision is 1e-6
-                let i = (f * 1e6_f64) as i64;
-                i.hash(state);
-            }
-        },
-        serde_json::Value::Bool(b) => b.hash(state),
-        serde_json::Value::Null => 0.hash(state),
+impl Display for ValueType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_serde())
+    }
+}
+
+/// Numbers as they are compared: integers exactly, other numbers rounded to 6 decimal places
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum NumberKey {
+    Integer(i128),
+    /// millionths of a number that is not integral after rounding
+    Fraction(i64),
+}
+
+fn float_key(f: f64) -> NumberKey {
+    let micros = (f * 1e6).round();
+    if micros.abs() >= 9e18 || micros % 1e6 == 0.0 {
+        NumberKey::Integer(f.round() as i128)
+    } else {
+        NumberKey::Fraction(micros as i64)
+    }
+}
+
+/// The integer written by `s`, if it is written the way integers are printed, like text map keys
+fn string_integer(s: &str) -> Option<i128> {
+    s.parse::<i128>().ok().filter(|i| i.to_string() == s)
+}
+
+/// Structural equality of values:
+/// - numbers are equal when they are equal after rounding to 6 decimal places, integers are compared exactly
+/// - a string is equal to an integer when it is the integer's decimal form, so text map keys equal the hashes in files
+/// - lists are equal when they have the same length and equal elements in order,
+///   objects when they have the same keys with equal values, in any order
+/// - values of other different types are never equal
+impl PartialEq for ValueType {
+    fn eq(&self, other: &Self) -> bool {
+        use ValueType::*;
+        match (self, other) {
+            (Null, Null) => true,
+            (Bool(x), Bool(y)) => x == y,
+            (Int(x), Int(y)) => x == y,
+            (Int(i), Float(f)) | (Float(f), Int(i)) => float_key(*f) == NumberKey::Integer(*i),
+            (Float(x), Float(y)) => float_key(*x) == float_key(*y),
+            (String(x), String(y)) => x == y,
+            (Int(i), String(s)) | (String(s), Int(i)) => string_integer(s) == Some(*i),
+            (Float(f), String(s)) | (String(s), Float(f)) => {
+                matches!(float_key(*f), NumberKey::Integer(i) if string_integer(s) == Some(i))
+            },
+            (List(x), List(y)) => x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| x == y),
+            (Object(x), Object(y)) => x.len() == y.len() && x.iter().all(|(k, v)| y.get(k) == Some(v)),
+            _ => false,
+        }
     }
 }
 
-impl Hash for NonVariableValue {
+impl Eq for ValueType {}
+
+/// Consistent with equality, each kind of value is tagged, and lists and objects with their length.
+/// Elements of lists and objects are hashed by their own hash, computed when they were made
+impl Hash for ValueType {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        hash_serde_value(state, &self.v);
+        match self {
+            ValueType::Null => 0_u8.hash(state),
+            ValueType::Bool(b) => {
+                1_u8.hash(state);
+                b.hash(state);
+            },
+            ValueType::Int(i) => {
+                2_u8.hash(state);
+                NumberKey::Integer(*i).hash(state);
+            },
+            ValueType::Float(f) => {
+                2_u8.hash(state);
+                float_key(*f).hash(state);
+            },
+            ValueType::String(s) => match string_integer(s) {
+                Some(i) => {
+                    2_u8.hash(state);
+                    NumberKey::Integer(i).hash(state);
+                },
+                None => {
+                    3_u8.hash(state);
+                    s.hash(state);
+                },
+            },
+            ValueType::List(arr) => {
+                4_u8.hash(state);
+                arr.len().hash(state);
+                arr.iter().for_each(|x| x.hash(state));
+            },
+            ValueType::Object(map) => {
+                5_u8.hash(state);
+                map.len().hash(state);
+                let mut keys: Vec<_> = map.keys().collect();
+                keys.sort();
+                for &k in keys.iter() {
+                    k.hash(state);
+                    map[k].hash(state);
+                }
+            },
+        }
     }
 }
 
+/// A value with its hash, which is only compared first to tell most different values apart quickly.
+/// The content is shared, so cloning a value, like a row bound to a variable, does not copy it
 #[derive(Debug, Clone)]
 pub struct Value {
-    pub value_type: NonVariableValue,
+    pub value_type: Arc<ValueType>,
     pub hash: u64,
 }
 
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && (Arc::ptr_eq(&self.value_type, &other.value_type) || self.value_type == other.value_type)
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
 impl Value {
+    pub fn from_serde(value: serde_json::Value) -> Self {
+        ValueType::from_serde(value).to_value()
+    }
+
+    /// Integral numbers are stored as integers, so that results of arithmetic print like the numbers in data files
     pub fn from_number(n: f64) -> Self {
-        let v = serde_json::json!(n);
-        NonVariableValue::from_serde(v).to_value()
+        if n.fract() == 0.0 && n.abs() < 9007199254740992.0 {
+            Self::from_int(n as i128)
+        } else {
+            ValueType::Float(n).to_value()
+        }
+    }
+
+    pub fn from_int(n: i128) -> Self {
+        ValueType::Int(n).to_value()
+    }
+
+    pub fn null() -> Self {
+        ValueType::Null.to_value()
     }
 
     pub fn from_string(s: &str) -> Self {
-        let v = serde_json::json!(s);
-        NonVariableValue::from_serde(v).to_value()
+        ValueType::String(String::from(s)).to_value()
+    }
+
+    pub fn to_serde(&self) -> serde_json::Value {
+        self.value_type.to_serde()
     }
 
-    pub fn get_serde_value(&self) -> &serde_json::Value {
-        &self.value_type.v
+    /// The content of the value, moved out of it unless it is shared
+    pub fn into_type(self) -> ValueType {
+        Arc::try_unwrap(self.value_type).unwrap_or_else(|x| x.as_ref().clone())
     }
 
     pub fn as_string(&self) -> Option<&str> {
-        match &self.value_type.v {
-            serde_json::Value::String(s) => Some(s.as_str()),
+        match self.value_type.as_ref() {
+            ValueType::String(s) => Some(s.as_str()),
             _ => None
         }
     }
 
-    pub fn as_loose_string(&self) -> Option<String> {
-        match &self.value_type.v {
-            serde_json::Value::String(s) => Some(s.clone()),
-            serde_json::Value::Number(n) => {
-                Some(n.as_f64().unwrap().to_string())
-            }
+    /// A number as a float, integers beyond 2^53 lose precision
+    pub fn as_number(&self) -> Option<f64> {
+        match self.value_type.as_ref() {
+            ValueType::Float(f) => Some(*f),
+            _ => self.as_int().map(|i| i as f64),
+        }
+    }
+
+    /// An integer, or a string written like one, the same way they are equal
+    pub fn as_int(&self) -> Option<i128> {
+        match self.value_type.as_ref() {
+            ValueType::Int(i) => Some(*i),
+            ValueType::String(s) => string_integer(s),
             _ => None
         }
     }
+
+    /// Strings as they are, and numbers as they are printed, other values have no text
+    pub fn as_text(&self) -> Option<String> {
+        match self.value_type.as_ref() {
+            ValueType::String(s) => Some(s.clone()),
+            ValueType::Int(i) => Some(i.to_string()),
+            ValueType::Float(f) => Some(f.to_string()),
+            _ => None
+        }
+    }
+
+    /// Order of two numbers, exact for two integers, `None` if either is not a number
+    pub fn compare_numbers(&self, other: &Value) -> Option<Ordering> {
+        match (self.as_int(), other.as_int()) {
+            (Some(x), Some(y)) => Some(x.cmp(&y)),
+            _ => self.as_number()?.partial_cmp(&other.as_number()?),
+        }
+    }
+
+    /// Order of values in `order by`: null, booleans, numbers, strings, then lists and objects, which are not ordered.
+    /// Strings written like integers are ordered as numbers
+    pub fn order(&self, other: &Value) -> Ordering {
+        fn rank(v: &Value) -> u8 {
+            match v.value_type.as_ref() {
+                ValueType::Null => 0,
+                ValueType::Bool(_) => 1,
+                _ if v.as_number().is_some() => 2,
+                ValueType::String(_) => 3,
+                _ => 4,
+            }
+        }
+
+        match (self.value_type.as_ref(), other.value_type.as_ref()) {
+            (ValueType::Bool(x), ValueType::Bool(y)) => x.cmp(y),
+            _ => match (rank(self), rank(other)) {
+                (2, 2) => match (self.as_int(), other.as_int()) {
+                    (Some(x), Some(y)) => x.cmp(&y),
+                    _ => self.as_number().unwrap().total_cmp(&other.as_number().unwrap()),
+                },
+                (3, 3) => self.as_string().unwrap().cmp(other.as_string().unwrap()),
+                (a, b) => a.cmp(&b),
+            },
+        }
+    }
+}
+
+/// Values are read from JSON directly, following the same rules as `ValueType::from_serde`
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::null())
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(ValueType::Bool(v).to_value())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::from_int(v as i128))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::from_int(v as i128))
+    }
+
+    fn visit_i128<E>(self, v: i128) -> Result<Value, E> {
+        Ok(Value::from_int(v))
+    }
+
+    fn visit_u128<E>(self, v: u128) -> Result<Value, E> {
+        Ok(match i128::try_from(v) {
+            Ok(x) => Value::from_int(x),
+            Err(_) => ValueType::Float(v as f64).to_value(),
+        })
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(ValueType::Float(v).to_value())
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::from_string(v))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(ValueType::String(v).to_value())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut result = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(x) = seq.next_element()? {
+            result.push(x);
+        }
+        Ok(ValueType::List(result).to_value())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut result = IndexMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((k, v)) = map.next_entry::<String, Value>()? {
+            result.insert(k, v);
+        }
+        Ok(ValueType::Object(result).to_value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(s: &str) -> Value {
+        Value::from_serde(serde_json::from_str(s).unwrap())
+    }
+
+    /// Equal values must hash the same, so they join
+    fn assert_same(x: &Value, y: &Value) {
+        assert_eq!(x, y);
+        assert_eq!(x.hash, y.hash);
+    }
+
+    #[test]
+    fn lists_are_compared_by_element() {
+        assert_ne!(json(r#"["ab"]"#), json(r#"["a", "b"]"#));
+        assert_ne!(json(r#"["a", "b"]"#), json(r#"["b", "a"]"#));
+        assert_ne!(json(r#"[1, 2]"#), json(r#"[[1, 2]]"#));
+        assert_same(&json(r#"[1, "x", null]"#), &json(r#"[1, "x", null]"#));
+    }
+
+    #[test]
+    fn integer_strings_equal_integers() {
+        assert_same(&json(r#""3796905611""#), &json("3796905611"));
+        assert_same(&Value::from_string("-12"), &Value::from_int(-12));
+        assert_ne!(json(r#""03""#), json("3"));
+        assert_ne!(json(r#""3.0""#), json("3"));
+        assert_ne!(json(r#""+3""#), json("3"));
+        assert_ne!(json(r#""true""#), json("true"));
+    }
+
+    #[test]
+    fn floats_are_rounded() {
+        assert_same(&json("1.0"), &json("1"));
+        assert_same(&Value::from_number(0.1 + 0.2), &json("0.3"));
+        assert_same(&json("2.0000000001"), &json("2"));
+        assert_ne!(json("0.5"), json("0.500001"));
+        assert_same(&json("3.0"), &json(r#""3""#));
+        assert_ne!(json("3.5"), json(r#""3.5""#));
+    }
+
+    #[test]
+    fn nan_equals_only_itself() {
+        let nan = ValueType::Float(f64::NAN).to_value();
+        assert_same(&nan, &ValueType::Float(f64::NAN).to_value());
+        assert_ne!(nan, json("0"));
+        assert_ne!(nan, json("0.0000001"));
+        assert_ne!(nan, Value::null());
+    }
+
+    #[test]
+    fn objects_ignore_key_order() {
+        assert_same(&json(r#"{"a": 1, "b": [2]}"#), &json(r#"{"b": [2], "a": 1}"#));
+        assert_ne!(json(r#"{"a": 1, "b": 2}"#), json(r#"{"a": 1}"#));
+        assert_ne!(json(r#"{"a": 1}"#), json(r#"{"b": 1}"#));
+    }
+
+    #[test]
+    fn types_do_not_mix() {
+        assert_ne!(json("null"), json("0"));
+        assert_ne!(json("false"), json("0"));
+        assert_ne!(json(r#""""#), json("null"));
+        assert_ne!(json("[]"), json("{}"));
+    }
 }